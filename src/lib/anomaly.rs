@@ -0,0 +1,242 @@
+use crate::lib::dense::TensorData;
+use crate::lib::float::Float;
+use crate::lib::grad::Data;
+use crate::lib::grad::GraphNode;
+use crate::lib::shared::{ Lock, Shared };
+
+use std::cell::Cell;
+use std::fmt::Display;
+
+thread_local! {
+    // Whether every new value and every gradient should be checked for NaN and infinities
+    static ANOMALY_ENABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Longest chain of operations shown in the error
+const MAX_CHAIN: usize = 16;
+
+pub fn is_anomaly_enabled() -> bool {
+    ANOMALY_ENABLED.with(|enabled| enabled.get())
+}
+
+// While alive, the first non-finite value or gradient panics with a description of where it came from.
+// The previous state is restored on drop, so guards can be nested.
+// Anomaly detection is a debugging aid that the demo binary does not turn on.
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct AnomalyGuard {
+    prev: bool,
+}
+
+impl AnomalyGuard {
+    pub fn enter() -> Self {
+        let prev = ANOMALY_ENABLED.with(|enabled| enabled.replace(true));
+        Self { prev }
+    }
+}
+
+impl Drop for AnomalyGuard {
+    fn drop(&mut self) {
+        ANOMALY_ENABLED.with(|enabled| enabled.set(self.prev));
+    }
+}
+
+// Runs the closure with anomaly detection, e.g. around a training step that ends up with NaN parameters
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn detect_anomaly<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = AnomalyGuard::enter();
+    f()
+}
+
+// Operations that led to the node, from the earliest one, for scalars and tensors alike.
+// Only the first operand that is itself the result of an operation is followed.
+fn chain<N: GraphNode>(node: &Shared<Lock<N>>) -> Vec<String> {
+    let mut ops: Vec<String> = Vec::new();
+    let mut current = Some(Shared::clone(node));
+
+    while let Some(node) = current.take() {
+        if ops.len() == MAX_CHAIN {
+            ops.push(String::from("..."));
+            break;
+        }
+
+        let data = node.borrow();
+        if let Some(op) = data.op_name() {
+            ops.push(op);
+            current = data
+                .parents()
+                .into_iter()
+                .find(|parent| parent.borrow().op_name().is_some());
+        }
+    }
+
+    ops.reverse();
+    ops
+}
+
+// Chain of a node that was just computed by `op`, which is not recorded yet
+fn forward_chain<N: GraphNode>(op: &dyn Display, operands: &[Shared<Lock<N>>]) -> Vec<String> {
+    let mut ops = match operands.iter().find(|operand| operand.borrow().op_name().is_some()) {
+        Some(operand) => chain(operand),
+        None => Vec::new(),
+    };
+    ops.push(op.to_string());
+    ops
+}
+
+fn values<T: Float>(nodes: &[Shared<Lock<Data<T>>>]) -> String {
+    let values: Vec<String> = nodes
+        .iter()
+        .map(|node| format!("{}", node.borrow().val))
+        .collect();
+    format!("[{}]", values.join(", "))
+}
+
+// Called with the value of a node that was just computed by `op` out of `operands`
+pub fn check_forward<T: Float>(val: T, op: &dyn Display, operands: &[Shared<Lock<Data<T>>>]) {
+    if val.is_finite() || !is_anomaly_enabled() {
+        return;
+    }
+
+    panic!(
+        "Anomaly detected in the forward pass: {} returned {}\n    operands: {}\n    chain: {}",
+        op,
+        val,
+        values(operands),
+        forward_chain(op, operands).join(" -> ")
+    );
+}
+
+// Called with the contribution of the gradient `grad` of `node` to one of its parents
+pub fn check_backward<T: Float>(node: &Shared<Lock<Data<T>>>, parent: &Shared<Lock<Data<T>>>, grad: T, contribution: T) {
+    if contribution.is_finite() || !is_anomaly_enabled() {
+        return;
+    }
+
+    let (operands, op, val) = {
+        let data = node.borrow();
+        match &data.dep {
+            Some(dep) => (dep.parents(), dep.name(), data.val),
+            None => (Vec::new(), String::from("leaf"), data.val),
+        }
+    };
+    let index = operands
+        .iter()
+        .position(|operand| Shared::ptr_eq(operand, parent))
+        .unwrap_or(0);
+
+    panic!(
+        "Anomaly detected in the backward pass: the gradient of {} with respect to operand {} is {}\n    operands: {}\n    value: {}, incoming gradient: {}\n    chain: {}",
+        op,
+        index,
+        contribution,
+        values(&operands),
+        val,
+        grad,
+        chain(node).join(" -> ")
+    );
+}
+
+fn shapes<T: Float>(nodes: &[Shared<Lock<TensorData<T>>>]) -> String {
+    let shapes: Vec<String> = nodes
+        .iter()
+        .map(|node| format!("{:?}", node.borrow().shape))
+        .collect();
+    shapes.join(", ")
+}
+
+// Called with the values of a tensor that were just computed by `op` out of `operands`
+pub fn check_tensor_forward<T: Float>(vals: &[T], op: &dyn Display, operands: &[Shared<Lock<TensorData<T>>>]) {
+    if !is_anomaly_enabled() {
+        return;
+    }
+    let index = match vals.iter().position(|val| !val.is_finite()) {
+        Some(index) => index,
+        None => {
+            return;
+        }
+    };
+
+    panic!(
+        "Anomaly detected in the forward pass: {} returned {} at element {}\n    operand shapes: {}\n    chain: {}",
+        op,
+        vals[index],
+        index,
+        shapes(operands),
+        forward_chain(op, operands).join(" -> ")
+    );
+}
+
+// Called with the contribution of the gradient of the tensor `node` to one of its parents
+pub fn check_tensor_backward<T: Float>(
+    node: &Shared<Lock<TensorData<T>>>,
+    parent: &Shared<Lock<TensorData<T>>>,
+    contribution: &[T]
+) {
+    if !is_anomaly_enabled() {
+        return;
+    }
+    let element = match contribution.iter().position(|grad| !grad.is_finite()) {
+        Some(element) => element,
+        None => {
+            return;
+        }
+    };
+
+    let (operands, op) = {
+        let data = node.borrow();
+        (data.parents(), data.op_name().unwrap_or(String::from("leaf")))
+    };
+    let index = operands
+        .iter()
+        .position(|operand| Shared::ptr_eq(operand, parent))
+        .unwrap_or(0);
+
+    panic!(
+        "Anomaly detected in the backward pass: the gradient of {} with respect to operand {} is {} at element {}\n    operand shapes: {}\n    chain: {}",
+        op,
+        index,
+        contribution[element],
+        element,
+        shapes(&operands),
+        chain(node).join(" -> ")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::dense::Tensor;
+    use crate::lib::grad::{ Nonlinear, Scalar };
+
+    #[test]
+    fn disabled_by_default() {
+        let x = Scalar::new(-1.0, true);
+        assert!(x.log().val().is_nan());
+
+        // The state is restored even when the closure panics
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| detect_anomaly(|| x.log())));
+        assert!(result.is_err());
+        assert!(!is_anomaly_enabled());
+    }
+
+    #[test]
+    #[should_panic(expected = "forward pass: log returned NaN\n    operands: [-2]\n    chain: * -> log")]
+    fn forward_anomaly() {
+        let x = Scalar::new(-1.0, true);
+        detect_anomaly(|| (&x * 2.0).log());
+    }
+
+    #[test]
+    #[should_panic(expected = "backward pass: the gradient of sqrt with respect to operand 0 is inf")]
+    fn backward_anomaly() {
+        let x = Scalar::new(0.0, true);
+        detect_anomaly(|| (&x.sqrt() * 2.0).backward());
+    }
+
+    #[test]
+    #[should_panic(expected = "forward pass: log returned NaN at element 1\n    operand shapes: [3]")]
+    fn tensor_anomaly() {
+        let x = Tensor::new(vec![1.0, -1.0, 2.0], &[3], true);
+        detect_anomaly(|| x.log());
+    }
+}
@@ -0,0 +1,156 @@
+// A building block for training loops, the demo binary builds a new graph at every step instead
+#![cfg_attr(not(test), allow(dead_code))]
+
+use crate::lib::float::Float;
+use crate::lib::grad::is_grad_enabled;
+use crate::lib::grad::Data;
+use crate::lib::grad::Scalar;
+use crate::lib::shared::{ Lock, Shared };
+use crate::lib::tensor::Tensor2D;
+
+// A graph traced once and replayed many times, e.g. for the training steps of a fixed-shape model.
+// Giving the named inputs new values and calling forward recomputes every node in place, without building
+// a new graph, and backward runs over the order sorted when tracing.
+// Only what was recorded while tracing is replayed: values computed inside no_grad and the code paths chosen
+// by looking at values (e.g. an if on Scalar::val) stay as they were when tracing. Scalar::select, on the other hand,
+// reads its mask again on every replay.
+pub struct CompiledGraph<T: Float = f32> {
+    inputs: Vec<(String, Tensor2D<T>)>,
+    outputs: Vec<Scalar<T>>,
+    // From the outputs to the leaves, see Scalar::topological_order
+    order: Vec<Shared<Lock<Data<T>>>>,
+}
+
+impl<T: Float> CompiledGraph<T> {
+    // Runs `f` once on leaves holding the values of the given inputs, recording the graph of its outputs.
+    // The leaves require grad, so that every operation depending on them is part of the graph.
+    pub fn trace(inputs: &[(&str, Tensor2D<T>)], f: impl FnOnce(&[Tensor2D<T>]) -> Vec<Scalar<T>>) -> Self {
+        assert!(is_grad_enabled(), "Cannot trace a graph while gradients are disabled");
+
+        let mut leaves: Vec<Tensor2D<T>> = Vec::new();
+        for (_, values) in inputs.iter() {
+            let leaf = Tensor2D::zeros(values.rows, values.cols, true);
+            for row in 0..values.rows {
+                for col in 0..values.cols {
+                    leaf.data[row][col].data.borrow_mut().val = values.data[row][col].val();
+                }
+            }
+            leaves.push(leaf);
+        }
+
+        let outputs = f(&leaves);
+        let order = Scalar::topological_order(&outputs);
+
+        Self {
+            inputs: inputs
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .zip(leaves)
+                .collect(),
+            outputs,
+            order,
+        }
+    }
+
+    // The leaves of an input, their grad holds d(outputs)/d(input) after backward
+    pub fn input(&self, name: &str) -> &Tensor2D<T> {
+        match self.inputs.iter().find(|(input, _)| input == name) {
+            Some((_, leaves)) => leaves,
+            None => panic!("The graph has no input named {}", name),
+        }
+    }
+
+    // Copies new values into an input and clears its gradient, call forward afterwards to update the outputs
+    pub fn set_input(&self, name: &str, values: &Tensor2D<T>) {
+        let leaves = self.input(name);
+        assert_eq!(leaves.rows, values.rows);
+        assert_eq!(leaves.cols, values.cols);
+
+        for row in 0..values.rows {
+            for col in 0..values.cols {
+                // Read the value before locking the leaf, which may be the very same node
+                let val = values.data[row][col].val();
+                let mut data = leaves.data[row][col].data.borrow_mut();
+                data.val = val;
+                data.grad = T::zero();
+            }
+        }
+    }
+
+    pub fn outputs(&self) -> &[Scalar<T>] {
+        &self.outputs
+    }
+
+    // Recomputes every node from the current values of the inputs and of the other leaves, e.g. updated parameters
+    pub fn forward(&self) {
+        for node in self.order.iter().rev() {
+            Data::forward(node);
+        }
+    }
+
+    // Backpropagates from every output, each seeded with 1.0, keeping the graph for the next replay.
+    // Like Scalar::backward, the gradients of the leaves accumulate across calls.
+    pub fn backward(&self) {
+        let seeds: Vec<T> = vec![T::one(); self.outputs.len()];
+        Scalar::backward_order(&self.order, &self.outputs, &seeds, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::grad::Activation;
+    use crate::lib::loss;
+    use crate::lib::nn::MLP;
+
+    #[test]
+    fn replay_matches_a_fresh_pass() {
+        let nn: MLP<f64> = MLP::new(vec![3, 4, 1], Activation::Tanh);
+        let y = vec![Tensor2D::scalar(1.0)];
+        let compiled = CompiledGraph::trace(&[("x", Tensor2D::row(vec![2.0, 3.0, -1.0]))], |inputs| {
+            vec![loss::mse(&vec![nn.forward(&inputs[0])], &y)]
+        });
+
+        let x = Tensor2D::row(vec![-0.5, 1.0, 0.25]);
+        compiled.set_input("x", &x);
+        compiled.forward();
+        nn.zero_grad();
+        compiled.backward();
+        let replayed: Vec<f64> = nn.params().iter().map(|param| param.borrow().grad).collect();
+        let replayed_x: Vec<f64> = compiled.input("x").data[0].iter().map(|x| x.grad()).collect();
+
+        // The same step on a graph built from scratch
+        let fresh_x = Tensor2D::zeros(1, 3, true);
+        for (leaf, val) in fresh_x.data[0].iter().zip(x.data[0].iter()) {
+            leaf.data.borrow_mut().val = val.val();
+        }
+        let fresh = loss::mse(&vec![nn.forward(&fresh_x)], &y);
+        nn.zero_grad();
+        fresh.backward();
+
+        assert!((compiled.outputs()[0].val() - fresh.val()).abs() < 1e-12);
+        for (param, replayed) in nn.params().iter().zip(replayed) {
+            assert!((param.borrow().grad - replayed).abs() < 1e-12);
+        }
+        for (leaf, replayed) in fresh_x.data[0].iter().zip(replayed_x) {
+            assert!((leaf.grad() - replayed).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn replay_switches_branches() {
+        // |x| written with a select, whose branch depends on the value of x
+        let compiled = CompiledGraph::trace(&[("x", Tensor2D::scalar(2.0))], |inputs| {
+            let x = &inputs[0].data[0][0];
+            vec![Scalar::select(&x.gt(&Scalar::new(0.0, false)), x, &(x * -1.0))]
+        });
+        compiled.backward();
+        assert_eq!(compiled.input("x").data[0][0].grad(), 1.0);
+
+        compiled.set_input("x", &Tensor2D::scalar(-3.0));
+        compiled.forward();
+        compiled.backward();
+        assert_eq!(compiled.outputs()[0].val(), 3.0);
+        assert_eq!(compiled.input("x").data[0][0].grad(), -1.0);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::lib::float::Float;
+use crate::lib::grad::Data;
+use crate::lib::grad::Dependency;
+use crate::lib::grad::Scalar;
+use crate::lib::shared::{ Lock, MaybeSync, Shared };
+use crate::lib::tensor::Tensor2D;
+
+// A user defined differentiable operation over any number of scalar inputs.
+// Once applied with Scalar::custom, it becomes a node of the graph like any built-in operation.
+pub trait CustomOp<T: Float = f32>: std::fmt::Debug + MaybeSync {
+    // Value of the operation for the given input values
+    fn forward(&self, inputs: &[T]) -> T;
+
+    // Gradients with respect to each input, given the input values,
+    // the output value and the gradient flowing into the output
+    fn backward(&self, inputs: &[T], output: T, grad: T) -> Vec<T>;
+
+    // Same gradients as backward, but built out of Scalar operations on the inputs, the output and the incoming
+    // gradient, so that they can be differentiated again. Only used by Scalar::backward_create_graph, which panics
+    // on operations that do not provide it: the plain gradients of backward would be treated as constants,
+    // silently giving wrong higher order derivatives.
+    fn backward_graph(&self, _inputs: &[Scalar<T>], _output: &Scalar<T>, _grad: &Scalar<T>) -> Option<Vec<Scalar<T>>> {
+        None
+    }
+
+    // Name used when displaying the operation
+    fn name(&self) -> String {
+        String::from("custom")
+    }
+}
+
+impl<T: Float> Scalar<T> {
+    pub fn custom(op: Shared<dyn CustomOp<T>>, inputs: &[Scalar<T>]) -> Scalar<T> {
+        let vals: Vec<T> = inputs
+            .iter()
+            .map(|input| input.val())
+            .collect();
+
+        let val = op.forward(&vals);
+        let operands: Vec<Shared<Lock<Data<T>>>> = inputs
+            .iter()
+            .map(|input| Shared::clone(&input.data))
+            .collect();
+        Scalar::from_op(val, Dependency::Custom { inputs: operands, op })
+    }
+}
+
+impl<T: Float> Tensor2D<T> {
+    // Applies the operation element-wise, the inputs must all have the same shape
+    pub fn custom(op: Shared<dyn CustomOp<T>>, inputs: &[Tensor2D<T>]) -> Tensor2D<T> {
+        assert!(!inputs.is_empty());
+
+        let (rows, cols) = (inputs[0].rows, inputs[0].cols);
+        let mut ans = Tensor2D::zeros(rows, cols, false);
+
+        for input in inputs.iter() {
+            assert_eq!(input.rows, rows);
+            assert_eq!(input.cols, cols);
+        }
+
+        for row in 0..rows {
+            for col in 0..cols {
+                let elements: Vec<Scalar<T>> = inputs
+                    .iter()
+                    .map(|input| input.data[row][col].clone())
+                    .collect();
+                ans.data[row][col] = Scalar::custom(Shared::clone(&op), &elements);
+            }
+        }
+
+        ans
+    }
+}
@@ -0,0 +1,1131 @@
+use crate::lib::anomaly;
+use crate::lib::float::Float;
+use crate::lib::grad::is_grad_enabled;
+use crate::lib::grad::topological_sort;
+use crate::lib::grad::Activation;
+use crate::lib::grad::GraphNode;
+use crate::lib::grad::Hook;
+use crate::lib::ops::broadcast_dim;
+use crate::lib::ops::Operation;
+use crate::lib::ops::Reduction;
+use crate::lib::shared::{ Lock, MaybeSync, Shared };
+
+use rand::distributions::Uniform;
+use rand::Rng;
+use std::collections::HashMap;
+use std::ops::{ Add, BitXor, Mul, Sub };
+
+// The values and the gradients of a tensor of any rank, each in a single contiguous buffer, in row-major order.
+// Unlike Tensor2D, the graph is recorded per tensor operation: a matmul is one node, not one node per element.
+#[derive(Debug)]
+pub struct TensorData<T: Float = f32> {
+    pub vals: Vec<T>,
+    pub grads: Vec<T>,
+    pub shape: Vec<usize>,
+    // Distance in the buffers between two consecutive elements along each dimension
+    pub strides: Vec<usize>,
+    pub dep: Option<TensorDependency<T>>,
+    // Run on every element of the incoming gradient, see Hook
+    pub hooks: Vec<Hook<T>>,
+    pub requires_grad: bool,
+    // See Data::released
+    pub released: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum TensorDependency<T: Float = f32> {
+    // Activation applied to every element
+    Single {
+        prev: Shared<Lock<TensorData<T>>>,
+        activation: Activation<T>,
+    },
+    // Element-wise operation, the operands being broadcast to the shape of the result
+    Double {
+        lhs: Shared<Lock<TensorData<T>>>,
+        rhs: Shared<Lock<TensorData<T>>>,
+        op: Operation,
+    },
+    // Product of (n, k) and (k, m) matrices, batched over the leading dimensions
+    Matmul {
+        lhs: Shared<Lock<TensorData<T>>>,
+        rhs: Shared<Lock<TensorData<T>>>,
+    },
+    // Same elements in a different shape, e.g. reshape, squeeze and unsqueeze
+    Reshape {
+        prev: Shared<Lock<TensorData<T>>>,
+    },
+    // Dimension i of the result is dimension dims[i] of prev
+    Permute {
+        prev: Shared<Lock<TensorData<T>>>,
+        dims: Vec<usize>,
+    },
+    // A block of prev starting at offset, selected by indexing its leading dimensions
+    Index {
+        prev: Shared<Lock<TensorData<T>>>,
+        offset: usize,
+    },
+    // Reduction of all the elements into a single one, only Sum and Mean apply
+    Reduce {
+        prev: Shared<Lock<TensorData<T>>>,
+        reduction: Reduction,
+    },
+}
+
+// Gradient sent to a parent, added to its own from the given offset
+type Contribution<T> = (Shared<Lock<TensorData<T>>>, usize, Vec<T>);
+
+#[derive(Debug, Clone)]
+pub struct Tensor<T: Float = f32> {
+    pub data: Shared<Lock<TensorData<T>>>,
+}
+
+// Strides of a contiguous row-major buffer with the given shape
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+// Product of a (n, k) and a (k, m) row-major matrix
+fn matmul<T: Float>(lhs: &[T], rhs: &[T], n: usize, k: usize, m: usize) -> Vec<T> {
+    let mut out = vec![T::zero(); n * m];
+    for i in 0..n {
+        for p in 0..k {
+            // Walk both the row of rhs and the row of out contiguously
+            let a = lhs[i * k + p];
+            for j in 0..m {
+                out[i * m + j] += a * rhs[p * m + j];
+            }
+        }
+    }
+    out
+}
+
+// Transpose of a (n, m) row-major matrix
+fn transpose<T: Float>(vals: &[T], n: usize, m: usize) -> Vec<T> {
+    let mut out = vec![T::zero(); n * m];
+    for i in 0..n {
+        for j in 0..m {
+            out[j * n + i] = vals[i * m + j];
+        }
+    }
+    out
+}
+
+// For each element of a view with the given shape and strides, in row-major order, its position in the buffer
+fn strided_offsets(shape: &[usize], strides: &[usize]) -> Vec<usize> {
+    let rank = shape.len();
+    let n: usize = shape.iter().product();
+    let mut offsets: Vec<usize> = Vec::with_capacity(n);
+    let mut index = vec![0; rank];
+    let mut offset = 0;
+
+    for _ in 0..n {
+        offsets.push(offset);
+        // Advance the index like an odometer, the last dimension first
+        for dim in (0..rank).rev() {
+            index[dim] += 1;
+            offset += strides[dim];
+            if index[dim] < shape[dim] {
+                break;
+            }
+            offset -= strides[dim] * shape[dim];
+            index[dim] = 0;
+        }
+    }
+
+    offsets
+}
+
+// For each element of the permuted tensor, in row-major order, its position in the buffer of the original one
+fn permuted_offsets(shape: &[usize], strides: &[usize], dims: &[usize]) -> Vec<usize> {
+    let shape: Vec<usize> = dims.iter().map(|&dim| shape[dim]).collect();
+    let strides: Vec<usize> = dims.iter().map(|&dim| strides[dim]).collect();
+    strided_offsets(&shape, &strides)
+}
+
+// Shape of the result of an element-wise operation: the shapes are aligned on their last dimension,
+// and a missing dimension or one of size 1 is repeated to match the other operand
+fn broadcast_shape(lhs: &[usize], rhs: &[usize]) -> Vec<usize> {
+    let rank = lhs.len().max(rhs.len());
+    (0..rank)
+        .map(|dim| {
+            // Dimensions counted from the end, those past the rank of an operand have size 1
+            let size = |shape: &[usize]| if dim + shape.len() >= rank { shape[dim + shape.len() - rank] } else { 1 };
+            broadcast_dim(size(lhs), size(rhs))
+        })
+        .collect()
+}
+
+// For each element of the broadcast result, in row-major order, the position of the operand element it uses.
+// Repeated dimensions have stride 0, so the same element is visited once per repetition.
+fn broadcast_offsets(shape: &[usize], strides: &[usize], out_shape: &[usize]) -> Vec<usize> {
+    let missing = out_shape.len() - shape.len();
+    let strides: Vec<usize> = (0..out_shape.len())
+        .map(|dim| if dim < missing || shape[dim - missing] == 1 { 0 } else { strides[dim - missing] })
+        .collect();
+    strided_offsets(out_shape, &strides)
+}
+
+// For each matrix of a batched matmul result, the index of the matrix of the operand it uses.
+// The batch dimensions are broadcast like the shapes of element-wise operations, e.g. for a shared weight.
+fn batch_offsets(batch: &[usize], out_batch: &[usize]) -> Vec<usize> {
+    broadcast_offsets(batch, &contiguous_strides(batch), out_batch)
+}
+
+// Splits the shape of a batched matmul operand into the batch dimensions and the matrix ones
+fn split_matrix(shape: &[usize]) -> (&[usize], usize, usize) {
+    assert!(shape.len() >= 2, "matmul needs operands of rank 2 at least, got {:?}", shape);
+    let rank = shape.len();
+    (&shape[..rank - 2], shape[rank - 2], shape[rank - 1])
+}
+
+impl<T: Float> TensorDependency<T> {
+    // Name of the operation that produced the tensor
+    pub fn name(&self) -> String {
+        match self {
+            TensorDependency::Single { activation, .. } => activation.to_string(),
+            TensorDependency::Double { op, .. } => op.to_string(),
+            TensorDependency::Matmul { .. } => String::from("matmul"),
+            TensorDependency::Reshape { .. } => String::from("reshape"),
+            TensorDependency::Permute { .. } => String::from("permute"),
+            TensorDependency::Index { .. } => String::from("index"),
+            TensorDependency::Reduce { reduction, .. } => reduction.to_string(),
+        }
+    }
+
+    pub fn parents(&self) -> Vec<Shared<Lock<TensorData<T>>>> {
+        match self {
+            TensorDependency::Single { prev, .. } |
+            TensorDependency::Reshape { prev } |
+            TensorDependency::Permute { prev, .. } |
+            TensorDependency::Index { prev, .. } |
+            TensorDependency::Reduce { prev, .. } => vec![Shared::clone(prev)],
+            TensorDependency::Double { lhs, rhs, .. } | TensorDependency::Matmul { lhs, rhs } => {
+                vec![Shared::clone(lhs), Shared::clone(rhs)]
+            }
+        }
+    }
+
+    // Moves the parents out of the dependency
+    fn into_parents(self, parents: &mut Vec<Shared<Lock<TensorData<T>>>>) {
+        match self {
+            TensorDependency::Single { prev, .. } |
+            TensorDependency::Reshape { prev } |
+            TensorDependency::Permute { prev, .. } |
+            TensorDependency::Index { prev, .. } |
+            TensorDependency::Reduce { prev, .. } => parents.push(prev),
+            TensorDependency::Double { lhs, rhs, .. } | TensorDependency::Matmul { lhs, rhs } => {
+                parents.push(lhs);
+                parents.push(rhs);
+            }
+        }
+    }
+}
+
+impl<T: Float> GraphNode for TensorData<T> {
+    fn parents(&self) -> Vec<Shared<Lock<Self>>> {
+        match &self.dep {
+            Some(dep) => dep.parents(),
+            None => Vec::new(),
+        }
+    }
+
+    fn op_name(&self) -> Option<String> {
+        self.dep.as_ref().map(|dep| dep.name())
+    }
+
+    fn requires_grad(&self) -> bool {
+        self.requires_grad
+    }
+
+    fn released(&self) -> bool {
+        self.released
+    }
+}
+
+impl<T: Float> Drop for TensorData<T> {
+    fn drop(&mut self) {
+        // Same as Data: tear down long chains of operations iteratively instead of recursively
+        let mut pending: Vec<Shared<Lock<TensorData<T>>>> = Vec::new();
+
+        if let Some(dep) = self.dep.take() {
+            dep.into_parents(&mut pending);
+        }
+
+        while let Some(rc) = pending.pop() {
+            if let Ok(cell) = Shared::try_unwrap(rc) {
+                if let Some(dep) = cell.into_inner().dep.take() {
+                    dep.into_parents(&mut pending);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Float> TensorData<T> {
+    pub fn hash(rc: &Shared<Lock<TensorData<T>>>) -> usize {
+        Shared::as_ptr(rc) as usize
+    }
+
+    // Runs f on both operands of a node, locking a single time when they are the same tensor, e.g. x.mul_elem(&x).
+    // With the `sync` feature a second read lock on the same node waits behind any queued writer, which deadlocks.
+    fn read_pair<R>(
+        lhs: &Shared<Lock<TensorData<T>>>,
+        rhs: &Shared<Lock<TensorData<T>>>,
+        f: impl FnOnce(&TensorData<T>, &TensorData<T>) -> R
+    ) -> R {
+        let l = lhs.borrow();
+        if Shared::ptr_eq(lhs, rhs) {
+            f(&l, &l)
+        } else {
+            f(&l, &rhs.borrow())
+        }
+    }
+
+    // Adds a contribution to the gradient of a parent from the given offset, constants do not keep one.
+    // Leaves receive it aside, in `leaves`, see Data::backward.
+    fn accumulate(
+        leaves: &mut HashMap<usize, Vec<T>>,
+        parent: &Shared<Lock<TensorData<T>>>,
+        offset: usize,
+        contribution: &[T]
+    ) {
+        let mut data = parent.borrow_mut();
+        if !data.requires_grad {
+            return;
+        }
+        let grads = match data.dep {
+            Some(_) => &mut data.grads,
+            None => {
+                let len = data.vals.len();
+                leaves.entry(TensorData::hash(parent)).or_insert_with(|| vec![T::zero(); len])
+            }
+        };
+        for (grad, c) in grads[offset..].iter_mut().zip(contribution.iter()) {
+            *grad += *c;
+        }
+    }
+
+    // Applies the chain rule to a node, adding the contribution of its gradient to each of its parents.
+    // The element-wise rules are the ones of Data::backward, see Activation::derivative and Operation::partials,
+    // and so is the way the gradients of the leaves are kept aside for the pass.
+    pub fn backward(rc: &Shared<Lock<TensorData<T>>>, leaves: &mut HashMap<usize, Vec<T>>) {
+        let hash = TensorData::hash(rc);
+        let leaf = rc.borrow().dep.is_none();
+        let incoming = match leaf {
+            true => leaves.remove(&hash).unwrap_or_else(|| vec![T::zero(); rc.borrow().vals.len()]),
+            false => std::mem::take(&mut rc.borrow_mut().grads),
+        };
+        let grad = TensorData::apply_hooks(rc, incoming);
+
+        // The contributions are computed first and handed over once the node is no longer locked
+        let contributions = TensorData::contributions(&rc.borrow(), &grad);
+        match leaf {
+            true => {
+                leaves.insert(hash, grad);
+            }
+            false => {
+                rc.borrow_mut().grads = grad;
+            }
+        }
+
+        for (parent, offset, contribution) in contributions.iter() {
+            anomaly::check_tensor_backward(rc, parent, contribution);
+            TensorData::accumulate(leaves, parent, *offset, contribution);
+        }
+    }
+
+    // Runs the hooks of the node on every element of its incoming gradient, see Data::backward
+    fn apply_hooks(rc: &Shared<Lock<TensorData<T>>>, grads: Vec<T>) -> Vec<T> {
+        let hooks = rc.borrow().hooks.clone();
+        if hooks.is_empty() {
+            return grads;
+        }
+
+        grads
+            .into_iter()
+            .map(|grad| hooks.iter().fold(grad, |grad, hook| (hook.0)(grad)))
+            .collect()
+    }
+
+    // The contribution of the gradient of the node to each of its parents
+    fn contributions(data: &TensorData<T>, grads: &[T]) -> Vec<Contribution<T>> {
+
+        match &data.dep {
+            Some(TensorDependency::Single { prev, activation }) => {
+                // Nothing flows back through a stop gradient
+                if matches!(activation, Activation::StopGradient) || !prev.borrow().requires_grad {
+                    return Vec::new();
+                }
+                let contribution: Vec<T> = {
+                    let prev = prev.borrow();
+                    (0..grads.len())
+                        .map(|i| grads[i] * activation.derivative(prev.vals[i], data.vals[i]))
+                        .collect()
+                };
+                vec![(Shared::clone(prev), 0, contribution)]
+            }
+
+            Some(TensorDependency::Double { lhs, rhs, op }) => {
+                // A broadcast element is used by many elements of the result, so it gets the sum of their gradients
+                let (lhs_contribution, rhs_contribution) = TensorData::read_pair(lhs, rhs, |l, r| {
+                    let lhs_offsets = broadcast_offsets(&l.shape, &l.strides, &data.shape);
+                    let rhs_offsets = broadcast_offsets(&r.shape, &r.strides, &data.shape);
+
+                    let mut lhs_contribution = vec![T::zero(); l.vals.len()];
+                    let mut rhs_contribution = vec![T::zero(); r.vals.len()];
+                    for i in 0..grads.len() {
+                        let (x, y) = (lhs_offsets[i], rhs_offsets[i]);
+                        let (dx, dy) = op.partials(l.vals[x], r.vals[y], data.vals[i]);
+                        lhs_contribution[x] += grads[i] * dx;
+                        rhs_contribution[y] += grads[i] * dy;
+                    }
+                    (lhs_contribution, rhs_contribution)
+                });
+                // When both operands are the same tensor, the two contributions simply add up
+                vec![(Shared::clone(lhs), 0, lhs_contribution), (Shared::clone(rhs), 0, rhs_contribution)]
+            }
+
+            Some(TensorDependency::Matmul { lhs, rhs }) => {
+                // Matmul means: f(L, R) = L R, dL = G R^T, dR = L^T G, for each matrix of the batch.
+                // A matrix broadcast over the batch, e.g. a shared weight, gets the sum of the gradients of its products.
+                let (lhs_contribution, rhs_contribution) = TensorData::read_pair(lhs, rhs, |l, r| {
+                    let (lhs_batch, n, k) = split_matrix(&l.shape);
+                    let (rhs_batch, _, m) = split_matrix(&r.shape);
+                    let (batch, _, _) = split_matrix(&data.shape);
+                    let lhs_offsets = batch_offsets(lhs_batch, batch);
+                    let rhs_offsets = batch_offsets(rhs_batch, batch);
+
+                    let mut lhs_contribution: Vec<T> = vec![T::zero(); if l.requires_grad { l.vals.len() } else { 0 }];
+                    let mut rhs_contribution: Vec<T> = vec![T::zero(); if r.requires_grad { r.vals.len() } else { 0 }];
+                    for (b, (x, y)) in lhs_offsets.into_iter().zip(rhs_offsets).enumerate() {
+                        let g = &grads[b * n * m..(b + 1) * n * m];
+                        if l.requires_grad {
+                            let rv = &r.vals[y * k * m..(y + 1) * k * m];
+                            let dl = matmul(g, &transpose(rv, k, m), n, m, k);
+                            for (acc, d) in lhs_contribution[x * n * k..(x + 1) * n * k].iter_mut().zip(dl) {
+                                *acc += d;
+                            }
+                        }
+                        if r.requires_grad {
+                            let lv = &l.vals[x * n * k..(x + 1) * n * k];
+                            let dr = matmul(&transpose(lv, n, k), g, k, n, m);
+                            for (acc, d) in rhs_contribution[y * k * m..(y + 1) * k * m].iter_mut().zip(dr) {
+                                *acc += d;
+                            }
+                        }
+                    }
+                    (lhs_contribution, rhs_contribution)
+                });
+                // Constants get no contribution, and do not keep one either
+                vec![(Shared::clone(lhs), 0, lhs_contribution), (Shared::clone(rhs), 0, rhs_contribution)]
+            }
+
+            Some(TensorDependency::Reshape { prev }) => {
+                // The elements keep their order, so does the gradient
+                vec![(Shared::clone(prev), 0, grads.to_vec())]
+            }
+
+            Some(TensorDependency::Permute { prev, dims }) => {
+                // Each element sends its gradient back to where it came from
+                let mut contribution = vec![T::zero(); grads.len()];
+                {
+                    let prev = prev.borrow();
+                    for (i, offset) in permuted_offsets(&prev.shape, &prev.strides, dims).into_iter().enumerate() {
+                        contribution[offset] = grads[i];
+                    }
+                }
+                vec![(Shared::clone(prev), 0, contribution)]
+            }
+
+            Some(TensorDependency::Index { prev, offset }) => {
+                // Only the selected block receives a gradient
+                vec![(Shared::clone(prev), *offset, grads.to_vec())]
+            }
+
+            Some(TensorDependency::Reduce { prev, reduction }) => {
+                let n = prev.borrow().vals.len();
+                let grad = match reduction {
+                    // Sum means: f(x) = x0 + x1 + ... + xn, f'(xi) = 1
+                    Reduction::Sum => grads[0],
+                    // Mean means: f(x) = (x0 + x1 + ... + xn) / n, f'(xi) = 1 / n
+                    Reduction::Mean => grads[0] / T::from_usize(n),
+                    Reduction::Dot => unreachable!("A tensor is never reduced with a dot product"),
+                };
+                vec![(Shared::clone(prev), 0, vec![grad; n])]
+            }
+
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<T: Float> Tensor<T> {
+    // A leaf holding the values, laid out in row-major order
+    pub fn new(vals: Vec<T>, shape: &[usize], requires_grad: bool) -> Self {
+        assert_eq!(
+            vals.len(),
+            shape.iter().product::<usize>(),
+            "{} values do not fit the shape {:?}",
+            vals.len(),
+            shape
+        );
+
+        Self {
+            data: Shared::new(
+                Lock::new(TensorData {
+                    grads: vec![T::zero(); vals.len()],
+                    vals,
+                    shape: shape.to_vec(),
+                    strides: contiguous_strides(shape),
+                    dep: None,
+                    hooks: Vec::new(),
+                    requires_grad,
+                    released: false,
+                })
+            ),
+        }
+    }
+
+    // Wraps the result of an operation, recording it when one of the operands requires grad
+    fn from_op(vals: Vec<T>, shape: &[usize], dep: TensorDependency<T>) -> Self {
+        let operands = dep.parents();
+        anomaly::check_tensor_forward(&vals, &dep.name(), &operands);
+        let requires_grad: bool =
+            is_grad_enabled() && operands.iter().any(|parent| parent.borrow().requires_grad);
+
+        let ans = Self::new(vals, shape, requires_grad);
+        if requires_grad {
+            ans.data.borrow_mut().dep = Some(dep);
+        }
+        ans
+    }
+
+    pub fn zeros(shape: &[usize], requires_grad: bool) -> Self {
+        Self::new(vec![T::zero(); shape.iter().product()], shape, requires_grad)
+    }
+
+    pub fn full(shape: &[usize], value: T, requires_grad: bool) -> Self {
+        Self::new(vec![value; shape.iter().product()], shape, requires_grad)
+    }
+
+    pub fn uniform(shape: &[usize], requires_grad: bool) -> Self {
+        let mut rng = rand::thread_rng();
+        let side = Uniform::new(-1.0, 1.0);
+        let vals: Vec<T> = (0..shape.iter().product())
+            .map(|_| T::from_f64(rng.sample(side)))
+            .collect();
+
+        Self::new(vals, shape, requires_grad)
+    }
+
+    // Uniform values scaled by the square root of the first dimension, as Tensor2D::xavier
+    pub fn xavier(shape: &[usize], requires_grad: bool) -> Self {
+        let mut rng = rand::thread_rng();
+        let side = Uniform::new(-1.0, 1.0);
+        let scale = (shape.first().copied().unwrap_or(1) as f64).sqrt();
+        let vals: Vec<T> = (0..shape.iter().product())
+            .map(|_| T::from_f64(rng.sample(side) / scale))
+            .collect();
+
+        Self::new(vals, shape, requires_grad)
+    }
+
+    // A constant matrix from its rows, as Tensor2D::from
+    pub fn from(vec: Vec<Vec<T>>) -> Self {
+        // Assert that the vector is not empty.
+        assert!(!vec.is_empty());
+
+        let (rows, cols) = (vec.len(), vec[0].len());
+        let vals: Vec<T> = vec.into_iter().flatten().collect();
+
+        Self::new(vals, &[rows, cols], false)
+    }
+
+    // From a scalar creates a 1x1 tensor.
+    pub fn scalar(scalar: T) -> Self {
+        Self::new(vec![scalar], &[1, 1], false)
+    }
+
+    // From a 1D array creates a 1xN tensor
+    pub fn row(vec: Vec<T>) -> Self {
+        let cols = vec.len();
+        Self::new(vec, &[1, cols], false)
+    }
+
+    pub fn col(vec: Vec<T>) -> Self {
+        let rows = vec.len();
+        Self::new(vec, &[rows, 1], false)
+    }
+
+    pub fn shape(&self) -> Vec<usize> {
+        self.data.borrow().shape.clone()
+    }
+
+    pub fn vals(&self) -> Vec<T> {
+        self.data.borrow().vals.clone()
+    }
+
+    pub fn grads(&self) -> Vec<T> {
+        self.data.borrow().grads.clone()
+    }
+
+    // Position of an element in the buffers
+    fn offset(&self, index: &[usize]) -> usize {
+        let data = self.data.borrow();
+        assert_eq!(index.len(), data.shape.len(), "Index {:?} does not match the shape {:?}", index, data.shape);
+
+        let mut offset = 0;
+        for (i, (&at, &size)) in index.iter().zip(data.shape.iter()).enumerate() {
+            assert!(at < size, "Index {:?} is out of bounds for the shape {:?}", index, data.shape);
+            offset += at * data.strides[i];
+        }
+        offset
+    }
+
+    pub fn get(&self, index: &[usize]) -> T {
+        let offset = self.offset(index);
+        self.data.borrow().vals[offset]
+    }
+
+    pub fn grad_at(&self, index: &[usize]) -> T {
+        let offset = self.offset(index);
+        self.data.borrow().grads[offset]
+    }
+
+    // The value of a tensor holding a single element, e.g. a loss
+    pub fn item(&self) -> T {
+        let data = self.data.borrow();
+        assert_eq!(data.vals.len(), 1, "Only a tensor with a single element has an item");
+        data.vals[0]
+    }
+
+    pub fn zero_grad(&self) {
+        for grad in self.data.borrow_mut().grads.iter_mut() {
+            *grad = T::zero();
+        }
+    }
+
+    // A leaf with the same values, cut from the graph
+    pub fn detach(&self) -> Tensor<T> {
+        let data = self.data.borrow();
+        Self::new(data.vals.clone(), &data.shape, false)
+    }
+
+    // Element-wise operation, broadcasting the operands to a common shape, e.g. a [1, n] bias over a [batch, n] tensor
+    fn zip_with(&self, rhs: &Tensor<T>, op: Operation) -> Tensor<T> {
+        let (vals, shape) = TensorData::read_pair(&self.data, &rhs.data, |lhs, rhs| {
+            let shape = broadcast_shape(&lhs.shape, &rhs.shape);
+
+            let vals: Vec<T> = broadcast_offsets(&lhs.shape, &lhs.strides, &shape)
+                .into_iter()
+                .zip(broadcast_offsets(&rhs.shape, &rhs.strides, &shape))
+                .map(|(x, y)| op.apply(lhs.vals[x], rhs.vals[y]))
+                .collect();
+            (vals, shape)
+        });
+
+        Self::from_op(vals, &shape, TensorDependency::Double {
+            lhs: Shared::clone(&self.data),
+            rhs: Shared::clone(&rhs.data),
+            op,
+        })
+    }
+
+    // Element-wise operation with a constant, broadcast as a rank 0 tensor
+    fn zip_scalar(&self, rhs: T, op: Operation) -> Tensor<T> {
+        self.zip_with(&Self::new(vec![rhs], &[], false), op)
+    }
+
+    // Element-wise product, `*` being the matrix product
+    pub fn mul_elem(&self, rhs: &Tensor<T>) -> Tensor<T> {
+        self.zip_with(rhs, Operation::Mul)
+    }
+
+    pub fn div_elem(&self, rhs: &Tensor<T>) -> Tensor<T> {
+        self.zip_with(rhs, Operation::Div)
+    }
+
+    pub fn max(&self, rhs: &Tensor<T>) -> Tensor<T> {
+        self.zip_with(rhs, Operation::Max)
+    }
+
+    pub fn min(&self, rhs: &Tensor<T>) -> Tensor<T> {
+        self.zip_with(rhs, Operation::Min)
+    }
+
+    // Matrix product over the last two dimensions, batched over the leading ones.
+    // The batch dimensions are broadcast, e.g. a [batch, n, k] input by a [k, m] weight shared by the whole batch.
+    pub fn matmul(&self, rhs: &Tensor<T>) -> Tensor<T> {
+        let (vals, shape) = TensorData::read_pair(&self.data, &rhs.data, |lhs, rhs| {
+            let (lhs_batch, n, k) = split_matrix(&lhs.shape);
+            let (rhs_batch, rhs_k, m) = split_matrix(&rhs.shape);
+            assert!(k == rhs_k, "Cannot multiply a {:?} by a {:?} tensor", lhs.shape, rhs.shape);
+
+            let mut shape = broadcast_shape(lhs_batch, rhs_batch);
+            let lhs_offsets = batch_offsets(lhs_batch, &shape);
+            let rhs_offsets = batch_offsets(rhs_batch, &shape);
+
+            let mut vals: Vec<T> = Vec::with_capacity(lhs_offsets.len() * n * m);
+            for (x, y) in lhs_offsets.into_iter().zip(rhs_offsets) {
+                vals.extend(
+                    matmul(&lhs.vals[x * n * k..(x + 1) * n * k], &rhs.vals[y * k * m..(y + 1) * k * m], n, k, m)
+                );
+            }
+
+            shape.extend([n, m]);
+            (vals, shape)
+        });
+
+        Self::from_op(vals, &shape, TensorDependency::Matmul {
+            lhs: Shared::clone(&self.data),
+            rhs: Shared::clone(&rhs.data),
+        })
+    }
+
+    // Swaps the last two dimensions
+    pub fn transpose(&self) -> Tensor<T> {
+        let rank = self.shape().len();
+        assert!(rank >= 2, "Only a tensor of rank 2 at least can be transposed");
+
+        let mut dims: Vec<usize> = (0..rank).collect();
+        dims.swap(rank - 2, rank - 1);
+        self.permute(&dims)
+    }
+
+    // Reorders the dimensions, dimension i of the result being dimension dims[i] of self
+    pub fn permute(&self, dims: &[usize]) -> Tensor<T> {
+        let (vals, shape) = {
+            let data = self.data.borrow();
+            let mut sorted = dims.to_vec();
+            sorted.sort_unstable();
+            assert!(
+                sorted.iter().copied().eq(0..data.shape.len()),
+                "{:?} is not a permutation of the dimensions of a {:?} tensor",
+                dims,
+                data.shape
+            );
+
+            let vals: Vec<T> = permuted_offsets(&data.shape, &data.strides, dims)
+                .into_iter()
+                .map(|offset| data.vals[offset])
+                .collect();
+            let shape: Vec<usize> = dims.iter().map(|&dim| data.shape[dim]).collect();
+            (vals, shape)
+        };
+
+        Self::from_op(vals, &shape, TensorDependency::Permute {
+            prev: Shared::clone(&self.data),
+            dims: dims.to_vec(),
+        })
+    }
+
+    // Same elements, in row-major order, with a different shape
+    pub fn reshape(&self, shape: &[usize]) -> Tensor<T> {
+        let vals = {
+            let data = self.data.borrow();
+            assert_eq!(
+                data.vals.len(),
+                shape.iter().product::<usize>(),
+                "Cannot reshape a {:?} tensor into {:?}",
+                data.shape,
+                shape
+            );
+            data.vals.clone()
+        };
+
+        Self::from_op(vals, shape, TensorDependency::Reshape {
+            prev: Shared::clone(&self.data),
+        })
+    }
+
+    // Removes a dimension of size 1
+    pub fn squeeze(&self, dim: usize) -> Tensor<T> {
+        let mut shape = self.shape();
+        assert!(dim < shape.len() && shape[dim] == 1, "Cannot squeeze dimension {} of a {:?} tensor", dim, shape);
+
+        shape.remove(dim);
+        self.reshape(&shape)
+    }
+
+    // Inserts a dimension of size 1 at position dim
+    pub fn unsqueeze(&self, dim: usize) -> Tensor<T> {
+        let mut shape = self.shape();
+        assert!(dim <= shape.len(), "Cannot unsqueeze dimension {} of a {:?} tensor", dim, shape);
+
+        shape.insert(dim, 1);
+        self.reshape(&shape)
+    }
+
+    // Indexes the leading dimensions, e.g. t.at(&[i]) is the i-th matrix of a batch and
+    // t.at(&[i, j]) its j-th row. Indexing every dimension gives a tensor of rank 0.
+    pub fn at(&self, index: &[usize]) -> Tensor<T> {
+        let (vals, shape, offset) = {
+            let data = self.data.borrow();
+            assert!(index.len() <= data.shape.len(), "Index {:?} has too many dimensions for a {:?} tensor", index, data.shape);
+
+            let mut offset = 0;
+            for (dim, &at) in index.iter().enumerate() {
+                assert!(at < data.shape[dim], "Index {:?} is out of bounds for the shape {:?}", index, data.shape);
+                offset += at * data.strides[dim];
+            }
+
+            let shape = data.shape[index.len()..].to_vec();
+            let len: usize = shape.iter().product();
+            (data.vals[offset..offset + len].to_vec(), shape, offset)
+        };
+
+        Self::from_op(vals, &shape, TensorDependency::Index {
+            prev: Shared::clone(&self.data),
+            offset,
+        })
+    }
+
+    // The slice at position index along dimension dim, which is removed
+    pub fn select(&self, dim: usize, index: usize) -> Tensor<T> {
+        let rank = self.shape().len();
+        assert!(dim < rank, "Cannot select along dimension {} of a tensor of rank {}", dim, rank);
+
+        if dim == 0 {
+            return self.at(&[index]);
+        }
+        // Bring the dimension to the front, then index it
+        let mut dims: Vec<usize> = vec![dim];
+        dims.extend((0..rank).filter(|&d| d != dim));
+        self.permute(&dims).at(&[index])
+    }
+
+    fn reduce(&self, reduction: Reduction) -> Tensor<T> {
+        let val = {
+            let data = self.data.borrow();
+            assert!(!data.vals.is_empty(), "Cannot reduce an empty tensor");
+            reduction.apply(&data.vals)
+        };
+
+        Self::from_op(vec![val], &[], TensorDependency::Reduce {
+            prev: Shared::clone(&self.data),
+            reduction,
+        })
+    }
+
+    // Sum of all the elements, as a tensor of rank 0
+    pub fn sum(&self) -> Tensor<T> {
+        self.reduce(Reduction::Sum)
+    }
+
+    // Mean of all the elements, as a tensor of rank 0
+    pub fn mean(&self) -> Tensor<T> {
+        self.reduce(Reduction::Mean)
+    }
+
+    pub fn nonlinear(tensor: &Self, activation: Activation<T>) -> Tensor<T> {
+        let (vals, shape) = {
+            let data = tensor.data.borrow();
+            let vals: Vec<T> = data.vals
+                .iter()
+                .map(|x| activation.apply(*x))
+                .collect();
+            (vals, data.shape.clone())
+        };
+
+        Self::from_op(vals, &shape, TensorDependency::Single {
+            prev: Shared::clone(&tensor.data),
+            activation,
+        })
+    }
+
+    pub fn tanh(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Tanh)
+    }
+
+    pub fn sigmoid(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Sigmoid)
+    }
+
+    pub fn relu(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::ReLU)
+    }
+
+    pub fn exp(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Exp)
+    }
+
+    pub fn log(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Log)
+    }
+
+    pub fn sqrt(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Sqrt)
+    }
+
+    pub fn abs(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Abs)
+    }
+
+    pub fn sin(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Sin)
+    }
+
+    pub fn cos(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Cos)
+    }
+
+    pub fn softplus(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Softplus)
+    }
+
+    pub fn leaky_relu(&self, slope: T) -> Tensor<T> {
+        Self::nonlinear(self, Activation::LeakyReLU(slope))
+    }
+
+    pub fn elu(&self, alpha: T) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Elu(alpha))
+    }
+
+    pub fn gelu(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Gelu)
+    }
+
+    pub fn silu(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::SiLU)
+    }
+
+    pub fn hard_tanh(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::HardTanh)
+    }
+
+    pub fn clamp(&self, lo: T, hi: T) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Clamp(lo, hi))
+    }
+
+    pub fn pow(&self, power: T) -> Tensor<T> {
+        Self::nonlinear(self, Activation::Pow(power))
+    }
+
+    pub fn stop_gradient(&self) -> Tensor<T> {
+        Self::nonlinear(self, Activation::StopGradient)
+    }
+
+    // Registers a closure that inspects or replaces every element of the incoming gradient, see Hook
+    pub fn register_hook(&self, hook: impl Fn(T) -> T + MaybeSync + 'static) {
+        self.data.borrow_mut().hooks.push(Hook(Shared::new(hook)));
+    }
+
+    // Backpropagates from every element, each seeded with 1.0, and frees the graph
+    pub fn backward(&self) -> Vec<Shared<Lock<TensorData<T>>>> {
+        self.backward_impl(false)
+    }
+
+    // Like Tensor::backward, but keeps the graph, see Scalar::backward_retain_graph
+    pub fn backward_retain_graph(&self) -> Vec<Shared<Lock<TensorData<T>>>> {
+        self.backward_impl(true)
+    }
+
+    fn backward_impl(&self, retain_graph: bool) -> Vec<Shared<Lock<TensorData<T>>>> {
+        let order = topological_sort(vec![Shared::clone(&self.data)]);
+
+        // Like Scalar::backward_order, interior gradients start from zero,
+        // while the leaves get the gradient of the pass aside and add it to their own at the end
+        for node in order.iter() {
+            let mut data = node.borrow_mut();
+            if data.dep.is_some() {
+                let len = data.grads.len();
+                data.grads = vec![T::zero(); len];
+            }
+        }
+        let mut leaves: HashMap<usize, Vec<T>> = HashMap::new();
+        let seed = vec![T::one(); self.data.borrow().vals.len()];
+        TensorData::accumulate(&mut leaves, &self.data, 0, &seed);
+
+        for node in order.iter() {
+            TensorData::backward(node, &mut leaves);
+        }
+
+        for node in order.iter() {
+            if let Some(grad) = leaves.get(&TensorData::hash(node)) {
+                for (acc, grad) in node.borrow_mut().grads.iter_mut().zip(grad.iter()) {
+                    *acc += *grad;
+                }
+            }
+        }
+
+        // Drop the links to the operands, see Scalar::backward_impl
+        if !retain_graph {
+            for node in order.iter() {
+                let mut data = node.borrow_mut();
+                if data.dep.is_some() {
+                    data.dep = None;
+                    data.released = true;
+                }
+            }
+        }
+
+        order
+    }
+}
+
+impl<T: Float> Add for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, Operation::Add)
+    }
+}
+
+impl<T: Float> Add<T> for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn add(self, rhs: T) -> Self::Output {
+        self.zip_scalar(rhs, Operation::Add)
+    }
+}
+
+impl<T: Float> Sub for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, Operation::Sub)
+    }
+}
+
+impl<T: Float> Sub<T> for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn sub(self, rhs: T) -> Self::Output {
+        self.zip_scalar(rhs, Operation::Sub)
+    }
+}
+
+// As for Tensor2D, `*` between tensors is the matrix product
+impl<T: Float> Mul for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.matmul(rhs)
+    }
+}
+
+impl<T: Float> Mul<T> for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        self.zip_scalar(rhs, Operation::Mul)
+    }
+}
+
+impl<T: Float> BitXor for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, Operation::Pow)
+    }
+}
+
+impl<T: Float> BitXor<T> for &Tensor<T> {
+    type Output = Tensor<T>;
+
+    fn bitxor(self, rhs: T) -> Self::Output {
+        self.pow(rhs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backward_retain_graph_keeps_the_graph() {
+        let x = Tensor::new(vec![1.0, -2.0], &[1, 2], true);
+        let w = Tensor::col(vec![0.5, 0.25]);
+        let y = (&x * &w).tanh();
+
+        y.backward_retain_graph();
+        y.backward();
+        let expected = 2.0 * (1.0 - y.item() * y.item());
+        assert!((x.grad_at(&[0, 0]) - expected * 0.5).abs() < 1e-6);
+        assert!((x.grad_at(&[0, 1]) - expected * 0.25).abs() < 1e-6);
+        assert!(y.data.borrow().released && !x.data.borrow().released);
+    }
+
+    #[test]
+    #[should_panic(expected = "freed by a previous backward pass")]
+    fn backward_through_a_freed_graph_panics() {
+        let h = Tensor::new(vec![1.0, -2.0], &[1, 2], true).exp();
+        (&h * 2.0).sum().backward();
+        (&h * 3.0).sum().backward();
+    }
+
+    #[test]
+    fn broadcast_shapes() {
+        let x = Tensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[3, 2], false);
+
+        let row = &x + &Tensor::row(vec![10.0, 20.0]);
+        assert_eq!((row.shape(), row.vals()), (vec![3, 2], vec![11.0, 22.0, 13.0, 24.0, 15.0, 26.0]));
+        let col = &x - &Tensor::col(vec![1.0, 2.0, 3.0]);
+        assert_eq!((col.shape(), col.vals()), (vec![3, 2], vec![0.0, 1.0, 1.0, 2.0, 2.0, 3.0]));
+        let scalar = &Tensor::scalar(1.0) + &x;
+        assert_eq!((scalar.shape(), scalar.vals()), (vec![3, 2], vec![2.0, 3.0, 4.0, 5.0, 6.0, 7.0]));
+
+        // Rank 0 and missing leading dimensions repeat over the whole tensor
+        let rank0 = &x * 2.0;
+        assert_eq!((rank0.shape(), rank0.vals()), (vec![3, 2], vec![2.0, 4.0, 6.0, 8.0, 10.0, 12.0]));
+        let missing = &Tensor::zeros(&[2, 3, 2], false) + &Tensor::new(vec![1.0, 2.0], &[2], false);
+        assert_eq!(missing.shape(), vec![2, 3, 2]);
+        assert_eq!((&Tensor::<f32>::zeros(&[], false) + &Tensor::zeros(&[], false)).shape(), Vec::<usize>::new());
+
+        // A column and a row broadcast to their outer shape
+        let outer = &Tensor::col(vec![1.0, 2.0, 3.0]) + &Tensor::row(vec![10.0, 20.0]);
+        assert_eq!((outer.shape(), outer.vals()), (vec![3, 2], vec![11.0, 21.0, 12.0, 22.0, 13.0, 23.0]));
+    }
+
+    #[test]
+    fn broadcasting_sums_the_gradient_over_the_repeated_dimensions() {
+        let x = Tensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[3, 2], false);
+        let (row, col) = (Tensor::zeros(&[1, 2], true), Tensor::zeros(&[3, 1], true));
+        let (scalar, rank0) = (Tensor::zeros(&[1, 1], true), Tensor::zeros(&[], true));
+
+        (&(&(&(&x + &row) - &col) + &scalar) + &rank0).sum().backward();
+        assert_eq!(row.grads(), vec![3.0, 3.0]);
+        assert_eq!(col.grads(), vec![-2.0, -2.0, -2.0]);
+        assert_eq!(scalar.grads(), vec![6.0]);
+        assert_eq!(rank0.grads(), vec![6.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot broadcast a dimension of size 3 with one of size 2")]
+    fn incompatible_shapes_do_not_broadcast() {
+        let _ = &Tensor::<f32>::zeros(&[3, 2], false) + &Tensor::zeros(&[2, 2], false);
+    }
+
+    // Run with cargo test --release -- --ignored, the timings of a debug build say little
+    #[test]
+    #[ignore]
+    fn matmul_is_faster_than_tensor2d() {
+        use crate::lib::tensor::Tensor2D;
+        use std::time::Instant;
+
+        // A chain of tanh layers, forward and backward
+        let (size, layers) = (64, 4);
+
+        let x = Tensor2D::<f32>::uniform(size, size, false);
+        let weights: Vec<Tensor2D<f32>> = (0..layers).map(|_| Tensor2D::xavier(size, size, true)).collect();
+        let start = Instant::now();
+        let y = weights.iter().fold(x, |x, w| (&x * w).tanh());
+        y.sum().backward();
+        let per_element = start.elapsed();
+
+        let x = Tensor::<f32>::uniform(&[size, size], false);
+        let weights: Vec<Tensor<f32>> = (0..layers).map(|_| Tensor::xavier(&[size, size], true)).collect();
+        let start = Instant::now();
+        let y = weights.iter().fold(x, |x, w| (&x * w).tanh());
+        y.sum().backward();
+        let dense = start.elapsed();
+
+        println!("Tensor2D: {:?}, Tensor: {:?}, speedup: {:.1}x", per_element, dense, per_element.as_secs_f64() / dense.as_secs_f64());
+        assert!(per_element > dense * 10);
+    }
+}
+
@@ -0,0 +1,216 @@
+use crate::lib::float::Float;
+use crate::lib::grad::Data;
+use crate::lib::grad::Dependency;
+use crate::lib::grad::Scalar;
+use crate::lib::shared::{ Lock, Shared };
+use crate::lib::tensor::Tensor2D;
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::Path;
+
+// Options of the Graphviz export, see Scalar::to_dot
+pub struct DotOptions<T: Float = f32> {
+    // Constants are not drawn as nodes, their values are listed in the label of the nodes using them instead
+    pub collapse_constants: bool,
+    // Nodes drawn together inside a box, e.g. MLP::dot_groups
+    pub groups: Vec<DotGroup<T>>,
+}
+
+impl<T: Float> Default for DotOptions<T> {
+    fn default() -> Self {
+        Self {
+            collapse_constants: false,
+            groups: Vec::new(),
+        }
+    }
+}
+
+// A labelled cluster of nodes, which can contain nested clusters
+pub struct DotGroup<T: Float = f32> {
+    pub label: String,
+    pub nodes: Vec<Shared<Lock<Data<T>>>>,
+    pub groups: Vec<DotGroup<T>>,
+}
+
+// Unique name of a node, derived from its address
+fn rc_2_str<T: Float>(rc: Shared<Lock<Data<T>>>) -> String {
+    let hex_string = format!("{:p}", Shared::as_ptr(&rc));
+    let clean_hex_string = hex_string.trim_start_matches("0x").to_uppercase();
+    // ADD NODE_ at the beginning
+    format!("NODE_{}", clean_hex_string)
+}
+
+// Leaves that do not require grad, e.g. the f32 operands of an operation
+fn is_constant<T: Float>(rc: &Shared<Lock<Data<T>>>) -> bool {
+    let data = rc.borrow();
+    data.dep.is_none() && !data.requires_grad
+}
+
+// Characters with a meaning inside record labels
+fn escape(label: &str) -> String {
+    let mut escaped = String::new();
+    for c in label.chars() {
+        if "{}|<>\"\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn write_group<T: Float>(dot: &mut String, group: &DotGroup<T>, visited: &HashSet<usize>, cluster: &mut usize, depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    writeln!(dot, "{}subgraph cluster_{} {{", indent, cluster).unwrap();
+    writeln!(dot, "{}    label=\"{}\";", indent, escape(&group.label)).unwrap();
+    *cluster += 1;
+
+    // Nodes that are not part of the exported graph are left out, they would show up disconnected
+    for node in group.nodes.iter() {
+        if visited.contains(&Data::hash(Shared::clone(node))) {
+            writeln!(dot, "{}    {};", indent, rc_2_str(Shared::clone(node))).unwrap();
+        }
+    }
+    for nested in group.groups.iter() {
+        write_group(dot, nested, visited, cluster, depth + 1);
+    }
+
+    writeln!(dot, "{}}}", indent).unwrap();
+}
+
+// Graphviz description of the graph leading to the roots.
+// Each node shows the operation that produced it, its value, its gradient and whether it requires grad.
+// Edges follow the forward pass, from the operands to the result.
+pub fn to_dot<T: Float>(roots: &[Scalar<T>], options: &DotOptions<T>) -> String {
+    // Collect every node reachable from the roots, constants included
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut nodes: Vec<Shared<Lock<Data<T>>>> = Vec::new();
+    let mut work: Vec<Shared<Lock<Data<T>>>> = roots
+        .iter()
+        .map(|root| Shared::clone(&root.data))
+        .collect();
+
+    while let Some(node) = work.pop() {
+        if !visited.insert(Data::hash(Shared::clone(&node))) {
+            continue;
+        }
+        if let Some(dep) = &node.borrow().dep {
+            work.extend(dep.parents());
+        }
+        nodes.push(node);
+    }
+
+    let mut dot = String::from("digraph {\n    rankdir=LR;\n    node [shape=record];\n");
+
+    for node in nodes.iter() {
+        if options.collapse_constants && is_constant(node) {
+            continue;
+        }
+
+        let data = node.borrow();
+        let mut fields: Vec<String> = Vec::new();
+
+        if let Some(dep) = &data.dep {
+            let mut op = dep.name();
+            if options.collapse_constants {
+                let constants: Vec<String> = dep
+                    .parents()
+                    .iter()
+                    .filter(|parent| is_constant(parent))
+                    .map(|parent| format!("{:.4}", parent.borrow().val))
+                    .collect();
+                if !constants.is_empty() {
+                    op = format!("{} ({})", op, constants.join(", "));
+                }
+            }
+            fields.push(escape(&op));
+        }
+        fields.push(format!("V: {:.4}", data.val));
+        fields.push(format!("G: {:.4}", data.grad));
+        if data.requires_grad {
+            fields.push(String::from("RG"));
+        }
+
+        writeln!(dot, "    {} [label=\"{}\"];", rc_2_str(Shared::clone(node)), fields.join(" | ")).unwrap();
+
+        if let Some(dep) = &data.dep {
+            for (i, parent) in dep.parents().iter().enumerate() {
+                if options.collapse_constants && is_constant(parent) {
+                    continue;
+                }
+                // The operands of a binary operation are told apart, since most of them are not commutative
+                let label = match (dep, i) {
+                    (Dependency::Double { .. }, 0) => " [label=\"lhs\"]",
+                    (Dependency::Double { .. }, _) => " [label=\"rhs\"]",
+                    (Dependency::Select { .. }, 0) => " [label=\"mask\"]",
+                    (Dependency::Select { .. }, 1) => " [label=\"a\"]",
+                    (Dependency::Select { .. }, _) => " [label=\"b\"]",
+                    _ => "",
+                };
+                writeln!(
+                    dot,
+                    "    {} -> {}{};",
+                    rc_2_str(Shared::clone(parent)),
+                    rc_2_str(Shared::clone(node)),
+                    label
+                ).unwrap();
+            }
+        }
+    }
+
+    let mut cluster: usize = 0;
+    for group in options.groups.iter() {
+        write_group(&mut dot, group, &visited, &mut cluster, 1);
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+impl<T: Float> Scalar<T> {
+    pub fn to_dot(&self, options: &DotOptions<T>) -> String {
+        to_dot(std::slice::from_ref(self), options)
+    }
+
+    // Writes the graph to a file, render it with e.g. `dot -Tsvg graph.dot -o graph.svg`
+    pub fn write_dot(&self, path: impl AsRef<Path>, options: &DotOptions<T>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_dot(options))
+    }
+}
+
+impl<T: Float> Tensor2D<T> {
+    // The graph leading to every element of the tensor
+    pub fn to_dot(&self, options: &DotOptions<T>) -> String {
+        let roots: Vec<Scalar<T>> = self.data.iter().flatten().cloned().collect();
+        to_dot(&roots, options)
+    }
+
+    pub fn write_dot(&self, path: impl AsRef<Path>, options: &DotOptions<T>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_dot(options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::grad::Activation;
+    use crate::lib::nn::MLP;
+
+    #[test]
+    fn groups_become_clusters() {
+        let nn: MLP = MLP::new(vec![2, 2, 1], Activation::Tanh);
+        let out = nn.forward(&Tensor2D::row(vec![1.0, -1.0]));
+
+        let dot = out.to_dot(&DotOptions { collapse_constants: true, groups: nn.dot_groups() });
+        assert!(dot.starts_with("digraph {") && dot.ends_with("}\n"));
+        // One cluster per layer, each holding one per neuron
+        assert_eq!(dot.matches("subgraph cluster_").count(), 2 + 2 + 1);
+        assert!(dot.contains("label=\"Layer 1\";") && dot.contains("label=\"Neuron 1\";"));
+
+        // Every parameter shows up inside its neuron
+        for param in nn.params() {
+            assert!(dot.contains(&format!("        {};", rc_2_str(param))));
+        }
+    }
+}
@@ -1,298 +1,1291 @@
+use crate::lib::anomaly;
+use crate::lib::custom::CustomOp;
+use crate::lib::float::Float;
 use crate::lib::ops::Operation;
-use std::cell::RefCell;
+use crate::lib::ops::Reduction;
+use crate::lib::shared::{ HookFn, Lock, MaybeSync, Shared };
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::collections::HashSet;
-use std::rc::Rc;
+
+thread_local! {
+    // Whether the operations should record the graph needed by the backward pass.
+    // It is per thread, so no_grad in one thread does not affect the others.
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|enabled| enabled.get())
+}
+
+// While alive, operations produce plain values with no dependency.
+// The previous state is restored on drop, so guards can be nested.
+pub struct NoGradGuard {
+    prev: bool,
+}
+
+impl NoGradGuard {
+    pub fn enter() -> Self {
+        let prev = GRAD_ENABLED.with(|enabled| enabled.replace(false));
+        Self { prev }
+    }
+}
+
+impl Drop for NoGradGuard {
+    fn drop(&mut self) {
+        GRAD_ENABLED.with(|enabled| enabled.set(self.prev));
+    }
+}
+
+// Runs the closure without building the graph, e.g. for inference
+pub fn no_grad<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = NoGradGuard::enter();
+    f()
+}
 
 #[derive(Debug, Clone)]
-pub enum Activation {
+pub enum Activation<T: Float = f32> {
     Exp,
     Tanh,
     Sigmoid,
     ReLU,
+    // Identity on the way forward, but no gradient flows back
+    StopGradient,
+    Log,
+    Sqrt,
+    Abs,
+    Sin,
+    Cos,
+    Softplus,
+    // Slope used for negative inputs
+    LeakyReLU(T),
+    // Alpha, the value approached for large negative inputs is -alpha
+    Elu(T),
+    Gelu,
+    SiLU,
+    HardTanh,
+    // Constant exponent
+    Pow(T),
+    // Lower and upper bounds
+    Clamp(T, T),
+}
+
+// Constants of the tanh approximation of GELU
+const GELU_K: f64 = 0.797_884_560_802_865_4; // sqrt(2 / pi)
+const GELU_C: f64 = 0.044_715;
+
+impl<T: Float> Activation<T> {
+    // Value of the activation at x, used both when building a node and when replaying a graph
+    pub fn apply(&self, x: T) -> T {
+        match self {
+            Activation::Tanh => x.tanh(),
+            Activation::Exp => x.exp(),
+            Activation::Sigmoid => T::one() / (T::one() + (-x).exp()),
+            Activation::ReLU => {
+                if x > T::zero() { x } else { T::zero() }
+            }
+            Activation::StopGradient => x,
+            Activation::Log => x.ln(),
+            Activation::Sqrt => x.sqrt(),
+            Activation::Abs => x.abs(),
+            Activation::Sin => x.sin(),
+            Activation::Cos => x.cos(),
+            // Written as max(x, 0) + ln(1 + e^-|x|) to avoid overflowing for large inputs
+            Activation::Softplus => x.max(T::zero()) + (-x.abs()).exp().ln_1p(),
+            Activation::LeakyReLU(slope) => {
+                if x > T::zero() { x } else { *slope * x }
+            }
+            Activation::Elu(alpha) => {
+                if x > T::zero() { x } else { *alpha * x.exp_m1() }
+            }
+            Activation::Gelu => {
+                let (k, c) = (T::from_f64(GELU_K), T::from_f64(GELU_C));
+                T::from_f64(0.5) * x * (T::one() + (k * (x + c * x.powi(3))).tanh())
+            }
+            Activation::SiLU => x / (T::one() + (-x).exp()),
+            Activation::HardTanh => x.clamp(-T::one(), T::one()),
+            Activation::Clamp(lo, hi) => x.max(*lo).min(*hi),
+            Activation::Pow(power) => x.powf(*power),
+        }
+    }
+
+    // Derivative of the activation at x, where y is the value it took there.
+    // Shared by the backward pass of Scalars and of Tensors.
+    pub fn derivative(&self, x: T, y: T) -> T {
+        match self {
+            // Tanh means: f(x) = tanh(x), f'(x) = 1 - tanh(x)^2
+            Activation::Tanh => T::one() - y.powi(2),
+            // Exp means: f(x) = e^x, f'(x) = e^x
+            Activation::Exp => y,
+            // Sigmoid means: f(x) = 1 / (1 + e^-x), f'(x) = f(x) * (1 - f(x))
+            Activation::Sigmoid => y * (T::one() - y),
+            // ReLU means: f(x) = max(0, x), f'(x) = 1 if x > 0, 0 otherwise
+            Activation::ReLU => {
+                if y > T::zero() { T::one() } else { T::zero() }
+            }
+            // Stop gradient means: f(x) = x, but the derivative is taken to be 0
+            Activation::StopGradient => T::zero(),
+            // Log means: f(x) = ln(x), f'(x) = 1 / x
+            Activation::Log => T::one() / x,
+            // Sqrt means: f(x) = sqrt(x), f'(x) = 1 / (2 * sqrt(x))
+            Activation::Sqrt => T::one() / (T::from_f64(2.0) * y),
+            // Abs means: f(x) = |x|, f'(x) = sign(x), taken to be 0 at 0
+            Activation::Abs => {
+                if x > T::zero() { T::one() } else if x < T::zero() { -T::one() } else { T::zero() }
+            }
+            // Sin means: f(x) = sin(x), f'(x) = cos(x)
+            Activation::Sin => x.cos(),
+            // Cos means: f(x) = cos(x), f'(x) = -sin(x)
+            Activation::Cos => -x.sin(),
+            // Softplus means: f(x) = ln(1 + e^x), f'(x) = sigmoid(x)
+            Activation::Softplus => T::one() / (T::one() + (-x).exp()),
+            // Leaky ReLU means: f(x) = x if x > 0, slope * x otherwise, f'(x) = 1 if x > 0, slope otherwise
+            Activation::LeakyReLU(slope) => {
+                if x > T::zero() { T::one() } else { *slope }
+            }
+            // ELU means: f(x) = x if x > 0, alpha * (e^x - 1) otherwise
+            // f'(x) = 1 if x > 0, alpha * e^x = f(x) + alpha otherwise
+            Activation::Elu(alpha) => {
+                if x > T::zero() { T::one() } else { y + *alpha }
+            }
+            // GELU (tanh approximation) means: f(x) = 0.5 * x * (1 + tanh(u)), u = k * (x + c * x^3)
+            // f'(x) = 0.5 * (1 + tanh(u)) + 0.5 * x * (1 - tanh(u)^2) * k * (1 + 3 * c * x^2)
+            Activation::Gelu => {
+                let (k, c) = (T::from_f64(GELU_K), T::from_f64(GELU_C));
+                let t = (k * (x + c * x.powi(3))).tanh();
+                T::from_f64(0.5) * (T::one() + t) + T::from_f64(0.5) * x * (T::one() - t.powi(2)) * k * (T::one() + T::from_f64(3.0) * c * x.powi(2))
+            }
+            // SiLU means: f(x) = x * sigmoid(x), f'(x) = sigmoid(x) * (1 + x * (1 - sigmoid(x)))
+            Activation::SiLU => {
+                let s = T::one() / (T::one() + (-x).exp());
+                s * (T::one() + x * (T::one() - s))
+            }
+            // Pow means: f(x) = x^p, f'(x) = p * x^(p - 1)
+            // For p = 0 the function is constant, even at x = 0 where x^(p - 1) is infinite.
+            Activation::Pow(power) => {
+                if *power == T::zero() { T::zero() } else { *power * x.powf(*power - T::one()) }
+            }
+            // Clamp means: f(x) = min(max(x, lo), hi), f'(x) = 1 if lo <= x <= hi, 0 otherwise
+            Activation::Clamp(lo, hi) => {
+                if x >= *lo && x <= *hi { T::one() } else { T::zero() }
+            }
+            // Hard tanh means: f(x) = clamp(x, -1, 1), f'(x) = 1 if -1 < x < 1, 0 otherwise
+            Activation::HardTanh => {
+                if x > -T::one() && x < T::one() { T::one() } else { T::zero() }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-pub enum Dependency {
+pub enum Dependency<T: Float = f32> {
     Single {
-        prev: Rc<RefCell<Data>>,
-        activation: Activation,
+        prev: Shared<Lock<Data<T>>>,
+        activation: Activation<T>,
     },
     Double {
-        lhs: Rc<RefCell<Data>>,
-        rhs: Rc<RefCell<Data>>,
+        lhs: Shared<Lock<Data<T>>>,
+        rhs: Shared<Lock<Data<T>>>,
         op: Operation,
     },
+    // Reduction over any number of inputs, in a single node.
+    // For Reduction::Dot the inputs hold the pairs to multiply next to each other: [x0, y0, x1, y1, ...]
+    Reduce {
+        inputs: Vec<Shared<Lock<Data<T>>>>,
+        reduction: Reduction,
+    },
+    // User defined operation over any number of inputs, see CustomOp
+    Custom {
+        inputs: Vec<Shared<Lock<Data<T>>>>,
+        op: Shared<dyn CustomOp<T>>,
+    },
+    // a if the mask is non zero, b otherwise, see Scalar::select
+    Select {
+        mask: Shared<Lock<Data<T>>>,
+        a: Shared<Lock<Data<T>>>,
+        b: Shared<Lock<Data<T>>>,
+    },
 }
+// A closure run on the incoming gradient of a node during the backward pass.
+// It returns the gradient to use from then on, so it can either inspect it or replace it.
+// Hooks only act on the plain gradients, not on the ones recorded by backward_create_graph.
+#[derive(Clone)]
+pub struct Hook<T: Float = f32>(pub Shared<HookFn<T>>);
+
+impl<T: Float> std::fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hook")
+    }
+}
+
+// Receives the gradient sent to a parent by Data::propagate
+pub type Accumulate<'a, T> = dyn FnMut(&Shared<Lock<Data<T>>>, T) + 'a;
+
 #[derive(Debug, Clone)]
-pub struct Data {
-    pub val: f32,
-    pub grad: f32,
-    pub dep: Option<Dependency>,
+pub struct Data<T: Float = f32> {
+    pub val: T,
+    pub grad: T,
+    // The gradient as a differentiable Scalar, only set by Scalar::backward_create_graph and only kept on leaves.
+    // It is built out of the graph that led to the leaf, forming a reference cycle through it, and keeps accumulating
+    // across passes, so reset it with Data::zero_grad once done to free that graph.
+    pub grad_graph: Option<Scalar<T>>,
+    pub hooks: Vec<Hook<T>>,
+    pub dep: Option<Dependency<T>>,
     pub requires_grad: bool,
+    // Set when a backward pass dropped the dependency of this node to free the graph,
+    // so that it is not mistaken for a leaf by a later pass. See Scalar::backward_retain_graph.
+    pub released: bool,
+    // Derivative of the value along the direction set on the leaves with Scalar::set_tangent, see grad::jvp.
+    // Computed along with the value, so a CompiledGraph replay does not update it.
+    pub tangent: Option<T>,
 }
 
-pub trait Nonlinear {
+// Tensor2D applies the activations through Scalar::unary, so the binary itself calls only some of these
+#[cfg_attr(not(test), allow(dead_code))]
+pub trait Nonlinear<T: Float = f32> {
     fn exp(&self) -> Self;
     fn tanh(&self) -> Self;
     fn sigmoid(&self) -> Self;
     fn relu(&self) -> Self;
-    fn pow(&self, power: usize) -> Self;
+    fn pow(&self, power: T) -> Self;
+    fn log(&self) -> Self;
+    fn sqrt(&self) -> Self;
+    fn abs(&self) -> Self;
+    fn sin(&self) -> Self;
+    fn cos(&self) -> Self;
+    fn softplus(&self) -> Self;
+    fn leaky_relu(&self, slope: T) -> Self;
+    fn elu(&self, alpha: T) -> Self;
+    fn gelu(&self) -> Self;
+    // Also known as swish
+    fn silu(&self) -> Self;
+    fn hard_tanh(&self) -> Self;
+    fn clamp(&self, lo: T, hi: T) -> Self;
+}
+
+// What the graph algorithms shared by Scalars and Tensors need to know about a node, see topological_sort
+pub trait GraphNode: Sized {
+    // The operands of the node, in order, none for leaves
+    fn parents(&self) -> Vec<Shared<Lock<Self>>>;
+    // Name of the operation that produced the node, None for leaves
+    fn op_name(&self) -> Option<String>;
+    fn requires_grad(&self) -> bool;
+    fn released(&self) -> bool;
+}
+
+// The nodes reachable from the roots that require grad, sorted topologically from the roots to the leaves.
+// This is the order a backward pass visits them in, the nodes shared by several roots appear once.
+pub fn topological_sort<N: GraphNode>(roots: Vec<Shared<Lock<N>>>) -> Vec<Shared<Lock<N>>> {
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut stack: Vec<Shared<Lock<N>>> = Vec::new();
+
+    // Depth first search with an explicit work list instead of recursion, so that
+    // long chains (e.g. big sums) do not overflow the call stack.
+    // Each entry carries a flag telling whether its parents have already been scheduled.
+    // The roots are pushed in reverse, so that they are explored in order.
+    let mut work: Vec<(Shared<Lock<N>>, bool)> = roots
+        .into_iter()
+        .rev()
+        .map(|root| (root, false))
+        .collect();
+
+    while let Some((node, expanded)) = work.pop() {
+        if expanded {
+            // All the parents have been pushed onto the stack, now it's the node's turn.
+            stack.push(node);
+            continue;
+        }
+
+        let parents = {
+            let data = node.borrow();
+            if data.released() {
+                panic!(
+                    "Trying to go through a graph that was freed by a previous backward pass, use backward_retain_graph to keep it"
+                );
+            }
+            if !data.requires_grad() || !visited.insert(Shared::as_ptr(&node) as usize) {
+                continue;
+            }
+            data.parents()
+        };
+
+        // Revisit the node once its parents are done
+        work.push((node, true));
+        // Push the parents in reverse, so that they are explored in order
+        for parent in parents.into_iter().rev() {
+            work.push((parent, false));
+        }
+    }
+
+    // Reverse the stack, since we want to backpropagate from the output to the input
+    stack.reverse();
+    stack
+}
+
+impl<T: Float> GraphNode for Data<T> {
+    fn parents(&self) -> Vec<Shared<Lock<Self>>> {
+        match &self.dep {
+            Some(dep) => dep.parents(),
+            None => Vec::new(),
+        }
+    }
+
+    fn op_name(&self) -> Option<String> {
+        self.dep.as_ref().map(|dep| dep.name())
+    }
+
+    fn requires_grad(&self) -> bool {
+        self.requires_grad
+    }
+
+    fn released(&self) -> bool {
+        self.released
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct Scalar {
-    pub data: Rc<RefCell<Data>>,
+pub struct Scalar<T: Float = f32> {
+    pub data: Shared<Lock<Data<T>>>,
 }
 
-impl Data {
-    pub fn hash(rc: Rc<RefCell<Data>>) -> usize {
-        Rc::as_ptr(&rc) as usize
+impl<T: Float> Data<T> {
+    pub fn hash(rc: Shared<Lock<Data<T>>>) -> usize {
+        Shared::as_ptr(&rc) as usize
     }
 
-    pub fn backward(rc: Rc<RefCell<Data>>) -> () {
-        let (dep, grad, val) = (&rc.borrow().dep, rc.borrow().grad, rc.borrow().val);
+    pub fn register_hook(rc: &Shared<Lock<Data<T>>>, hook: impl Fn(T) -> T + MaybeSync + 'static) {
+        rc.borrow_mut().hooks.push(Hook(Shared::new(hook)));
+    }
 
-        match dep {
+    // Resets the gradient of the node, including the differentiable one
+    pub fn zero_grad(rc: &Shared<Lock<Data<T>>>) {
+        let grad_graph = {
+            let mut data = rc.borrow_mut();
+            data.grad = T::zero();
+            data.grad_graph.take()
+        };
+        // The graph of the gradient holds the node, so drop it once the lock is released
+        drop(grad_graph);
+    }
+
+    // Runs the hooks of the node, in registration order, on its incoming gradient
+    fn apply_hooks(rc: &Shared<Lock<Data<T>>>, grad: T) -> T {
+        // Clone the hooks, so that they are free to borrow the node themselves
+        let hooks: Vec<Hook<T>> = rc.borrow().hooks.clone();
+        hooks.iter().fold(grad, |grad, hook| (hook.0)(grad))
+    }
+
+    // Leaves that require grad keep accumulating across passes, while the other nodes only hold the gradient of the last one
+    fn accumulates(&self) -> bool {
+        self.dep.is_none() && self.requires_grad
+    }
+
+    // Hands the gradient of the node over to its parents, once the hooks have seen it.
+    // Interior nodes belong to a single pass and hold their gradient in the grad field. Leaves can be shared
+    // by several passes, e.g. the parameters of a model trained on several threads, so their gradient of this pass
+    // is kept aside in `leaves`, indexed by node: that way their hooks never see what the other passes accumulated.
+    pub fn backward(rc: &Shared<Lock<Data<T>>>, leaves: &mut HashMap<usize, T>) {
+        // Read the gradient first, so that no lock on the node is held while the hooks run
+        let (grad, leaf) = {
+            let data = rc.borrow();
+            (data.grad, data.accumulates())
+        };
+
+        let grad = if leaf {
+            let hash = Data::hash(Shared::clone(rc));
+            let grad = Data::apply_hooks(rc, leaves.get(&hash).copied().unwrap_or(T::zero()));
+            leaves.insert(hash, grad);
+            grad
+        } else {
+            let hooked = Data::apply_hooks(rc, grad);
+            if hooked != grad {
+                rc.borrow_mut().grad = hooked;
+            }
+            hooked
+        };
+
+        Data::propagate(rc, grad, &mut |parent, contribution| {
+            let mut data = parent.borrow_mut();
+            if data.accumulates() {
+                *leaves.entry(Data::hash(Shared::clone(parent))).or_insert(T::zero()) += contribution;
+            } else {
+                data.grad += contribution;
+            }
+        });
+    }
+
+    // Recomputes the value of a node from the current values of its operands, e.g. to replay a CompiledGraph.
+    // Leaves keep their value.
+    pub fn forward(rc: &Shared<Lock<Data<T>>>) {
+        let (val, op, operands) = {
+            let data = rc.borrow();
+            if data.released {
+                panic!("Trying to replay a graph that was freed by a backward pass, use backward_retain_graph to keep it");
+            }
+            let dep = match &data.dep {
+                Some(dep) => dep,
+                None => {
+                    return;
+                }
+            };
+            let operands = dep.parents();
+            let vals: Vec<T> = operands
+                .iter()
+                .map(|operand| operand.borrow().val)
+                .collect();
+
+            let val = match dep {
+                Dependency::Single { activation, .. } => activation.apply(vals[0]),
+                Dependency::Double { op, .. } => op.apply(vals[0], vals[1]),
+                Dependency::Reduce { reduction, .. } => reduction.apply(&vals),
+                Dependency::Custom { op, .. } => op.forward(&vals),
+                Dependency::Select { .. } => if vals[0] != T::zero() { vals[1] } else { vals[2] },
+            };
+            (val, dep.name(), operands)
+        };
+
+        anomaly::check_forward(val, &op, &operands);
+        rc.borrow_mut().val = val;
+    }
+
+    // Applies the chain rule to a node whose output gradient is `grad`,
+    // handing the contribution for each parent to `accumulate`.
+    pub fn propagate(
+        rc: &Shared<Lock<Data<T>>>,
+        grad: T,
+        accumulate: &mut Accumulate<'_, T>
+    ) {
+        // In anomaly mode every contribution is checked before being handed over
+        let anomaly = anomaly::is_anomaly_enabled();
+        let accumulate = &mut |parent: &Shared<Lock<Data<T>>>, contribution: T| {
+            if anomaly {
+                anomaly::check_backward(rc, parent, grad, contribution);
+            }
+            accumulate(parent, contribution);
+        };
+
+        // Copy the dependency out, so that the node is not locked while its parents are
+        let (dep, val) = {
+            let data = rc.borrow();
+            (data.dep.clone(), data.val)
+        };
+
+        match &dep {
             Some(Dependency::Double { lhs, rhs, op }) => {
                 // When we have an operation, we need to apply the chain rule to calculate the derivative.
                 // The chain rule states that the derivative of a function f(g(x)) is f'(g(x)) * g\"(x).
+                // When both operands are the same node, the two contributions simply add up.
+                let x = lhs.borrow().val;
+                let y = rhs.borrow().val;
+                let (dx, dy) = op.partials(x, y, val);
 
-                match op {
-                    Operation::Add => {
-                        // Addition means: f(x) = x + y, f'(x) = 1, f'(y) = 1
-                        // So we just accumulate the gradient by 1.0 times the gradient of the output.
-                        if lhs.as_ptr() == rhs.as_ptr() {
-                            lhs.borrow_mut().grad += grad * 2.0;
-                        } else {
-                            lhs.borrow_mut().grad += grad * 1.0;
-                            rhs.borrow_mut().grad += grad * 1.0;
-                        }
-                    }
-                    Operation::Sub => {
-                        // Subtraction means: f(x) = x - y, f'(x) = 1, f'(y) = -1
-                        // So we just accumulate the gradient by 1.0 or -1.0 times the gradient of the output.
-                        if lhs.as_ptr() == rhs.as_ptr() {
-                            // Do nothing since we would sum and subtract the same quantity
-                        } else {
-                            lhs.borrow_mut().grad += grad * 1.0;
-                            rhs.borrow_mut().grad += grad * -1.0;
+                accumulate(lhs, grad * dx);
+                accumulate(rhs, grad * dy);
+            }
+
+            Some(Dependency::Reduce { inputs, reduction }) => {
+                match reduction {
+                    Reduction::Sum => {
+                        // Sum means: f(x) = x0 + x1 + ... + xn, f'(xi) = 1
+                        for input in inputs.iter() {
+                            accumulate(input, grad);
                         }
                     }
-                    Operation::Mul => {
-                        // Multiplication means: f(x) = x * y, f'(x) = y, f'(y) = x
-                        // So we just accumulate the gradient by the other parent times the gradient of the output.
-                        if lhs.as_ptr() == rhs.as_ptr() {
-                            let mut data_ref = lhs.borrow_mut();
-                            data_ref.grad += 2.0 * grad * data_ref.val;
-                        } else {
-                            let (mut lhs_ref, mut rhs_ref) = (lhs.borrow_mut(), rhs.borrow_mut());
-
-                            lhs_ref.grad += grad * rhs_ref.val;
-                            rhs_ref.grad += grad * lhs_ref.val;
+                    Reduction::Mean => {
+                        // Mean means: f(x) = (x0 + x1 + ... + xn) / n, f'(xi) = 1 / n
+                        let n = T::from_usize(inputs.len());
+                        for input in inputs.iter() {
+                            accumulate(input, grad / n);
                         }
                     }
-                    Operation::Div => {
-                        // Division means: f(x) = x / y, f'(x) = 1 / y, f'(y) = -x / y^2
-                        // So, for the left hand side, we accumulate by 1.0 divided by the right hand side times the gradient of the output.
-                        // For the right hand side, we accumulate by -1.0 times the left hand side divided by the right hand side squared times the gradient of the output.
-                        if lhs.as_ptr() == rhs.as_ptr() {
-                            let mut data_ref = lhs.borrow_mut();
-                            data_ref.grad += grad / data_ref.val;
-                            data_ref.grad += (grad * -1.0 * data_ref.val) / data_ref.val.powi(2);
-                        } else {
-                            let (mut lhs_ref, mut rhs_ref) = (lhs.borrow_mut(), rhs.borrow_mut());
-
-                            lhs_ref.grad += grad / rhs_ref.val;
-                            rhs_ref.grad += (grad * -1.0 * lhs_ref.val) / rhs_ref.val.powi(2);
+                    Reduction::Dot => {
+                        // Dot means: f(x, y) = x0 * y0 + ... + xn * yn, f'(xi) = yi, f'(yi) = xi
+                        for pair in inputs.chunks(2) {
+                            let x = pair[0].borrow().val;
+                            let y = pair[1].borrow().val;
+                            accumulate(&pair[0], grad * y);
+                            accumulate(&pair[1], grad * x);
                         }
                     }
                 }
             }
 
+            Some(Dependency::Custom { inputs, op }) => {
+                // Custom operation: the derivatives are provided by the operation itself
+                let vals: Vec<T> = inputs
+                    .iter()
+                    .map(|input| input.borrow().val)
+                    .collect();
+                let grads: Vec<T> = op.backward(&vals, val, grad);
+                assert_eq!(grads.len(), inputs.len(), "{} must return one gradient per input", op.name());
+
+                for (input, input_grad) in inputs.iter().zip(grads) {
+                    accumulate(input, input_grad);
+                }
+            }
+
+            Some(Dependency::Select { mask, a, b }) => {
+                // Select means: f(m, a, b) = a if m != 0, b otherwise, f'(a) = 1 if m != 0, f'(b) = 1 if m = 0
+                // The mask is piecewise constant, so nothing flows back to it.
+                let selected = if mask.borrow().val != T::zero() { a } else { b };
+                accumulate(selected, grad);
+            }
+
+            Some(Dependency::Single { activation: Activation::StopGradient, .. }) => {
+                // Stop gradient means: f(x) = x, but the derivative is taken to be 0
+                // So, nothing flows back to the parent.
+            }
+
             Some(Dependency::Single { prev, activation }) => {
-                match activation {
-                    Activation::Tanh => {
-                        // Tanh means: f(x) = tanh(x), f'(x) = 1 - tanh(x)^2
-                        // So, we set the gradient of the parent to 1 - tanh(x)^2 times the gradient of the output.
-                        prev.borrow_mut().grad += grad * (1.0 - val.powi(2));
+                let x = prev.borrow().val;
+                accumulate(prev, grad * activation.derivative(x, val));
+            }
+            None => (),
+        }
+    }
+
+    // Accumulates a differentiable gradient into the node
+    fn accumulate_graph(rc: &Shared<Lock<Data<T>>>, grad: Scalar<T>) {
+        // Constants are never visited by the backward pass, no need to keep their gradient
+        if !rc.borrow().requires_grad {
+            return;
+        }
+
+        let prev = rc.borrow().grad_graph.clone();
+        let acc = match prev {
+            Some(prev) => &prev + &grad,
+            None => grad,
+        };
+        rc.borrow_mut().grad_graph = Some(acc);
+    }
+
+    // Same rules as Data::backward, but the gradients are built out of Scalar operations.
+    // This records a graph for the gradients themselves, so they can be differentiated again.
+    pub fn backward_graph(rc: Shared<Lock<Data<T>>>) {
+        let (dep, grad) = {
+            let data = rc.borrow();
+            (data.dep.clone(), data.grad_graph.clone())
+        };
+        let (out, grad) = match grad {
+            Some(grad) => (Scalar { data: Shared::clone(&rc) }, grad),
+            None => {
+                return;
+            }
+        };
+
+        match dep {
+            Some(Dependency::Double { lhs, rhs, op }) => {
+                let (x, y) = (Scalar { data: Shared::clone(&lhs) }, Scalar { data: Shared::clone(&rhs) });
+
+                // Compute both contributions before accumulating them,
+                // so that the same operand on both sides is handled for free.
+                let (lhs_grad, rhs_grad) = match op {
+                    // f(x, y) = x + y, f'(x) = 1, f'(y) = 1
+                    Operation::Add => (grad.clone(), grad),
+                    // f(x, y) = x - y, f'(x) = 1, f'(y) = -1
+                    Operation::Sub => (grad.clone(), &grad * -T::one()),
+                    // f(x, y) = x * y, f'(x) = y, f'(y) = x
+                    Operation::Mul => (&grad * &y, &grad * &x),
+                    // f(x, y) = x / y, f'(x) = 1 / y, f'(y) = -x / y^2
+                    Operation::Div => (&grad / &y, &(&(&grad * &x) / &(&y * &y)) * -T::one()),
+                    // f(x, y) = x^y, f'(x) = y * x^(y - 1), f'(y) = x^y * ln(x) for x > 0, 0 otherwise
+                    Operation::Pow => {
+                        let rhs_grad = match x.val() > T::zero() {
+                            true => &grad * &(&out * &x.log()),
+                            false => &grad * T::zero(),
+                        };
+                        let lhs_grad = match y.val() == T::zero() {
+                            true => &grad * T::zero(),
+                            false => &grad * &(&y * &(&x ^ &(&y - T::one()))),
+                        };
+                        (lhs_grad, rhs_grad)
                     }
-                    Activation::Exp => {
-                        // Exp means: f(x) = e^x, f'(x) = e^x
-                        // So, we set the gradient of the parent to e^x times the gradient of the output.
-                        prev.borrow_mut().grad += grad * val;
+                    // The gradient goes to the selected operand, split evenly on ties
+                    Operation::Max | Operation::Min => {
+                        let (lhs_weight, rhs_weight) = Operation::select_weights(&op, x.val(), y.val());
+                        (&grad * lhs_weight, &grad * rhs_weight)
                     }
-                    Activation::Sigmoid => {
-                        // Sigmoid means: f(x) = 1 / (1 + e^-x), f'(x) = f(x) * (1 - f(x))
-                        // So, we set the gradient of the parent to f(x) * (1 - f(x)) times the gradient of the output.
-                        prev.borrow_mut().grad += grad * val * (1.0 - val);
+                    // Comparisons have a zero derivative
+                    Operation::Gt | Operation::Ge | Operation::Lt | Operation::Le | Operation::Eq | Operation::Ne => {
+                        (&grad * T::zero(), &grad * T::zero())
                     }
-                    Activation::ReLU => {
-                        // ReLU means: f(x) = max(0, x), f'(x) = 1 if x > 0, 0 otherwise
-                        // So, we set the gradient of the parent to 1 if x > 0, 0 otherwise times the gradient of the output.
+                };
+
+                Data::accumulate_graph(&lhs, lhs_grad);
+                Data::accumulate_graph(&rhs, rhs_grad);
+            }
 
-                        prev.borrow_mut().grad += grad * (if val > 0.0 { 1.0 } else { 0.0 });
+            Some(Dependency::Reduce { inputs, reduction }) => {
+                match reduction {
+                    // f'(xi) = 1
+                    Reduction::Sum => {
+                        for input in inputs.iter() {
+                            Data::accumulate_graph(input, grad.clone());
+                        }
+                    }
+                    // f'(xi) = 1 / n
+                    Reduction::Mean => {
+                        let grad = &grad * (T::one() / T::from_usize(inputs.len()));
+                        for input in inputs.iter() {
+                            Data::accumulate_graph(input, grad.clone());
+                        }
                     }
+                    // f'(xi) = yi, f'(yi) = xi
+                    Reduction::Dot => {
+                        for pair in inputs.chunks(2) {
+                            let (x, y) = (Scalar { data: Shared::clone(&pair[0]) }, Scalar { data: Shared::clone(&pair[1]) });
+                            Data::accumulate_graph(&pair[0], &grad * &y);
+                            Data::accumulate_graph(&pair[1], &grad * &x);
+                        }
+                    }
+                }
+            }
+
+            Some(Dependency::Custom { inputs, op }) => {
+                // Custom operation: the differentiable derivatives are provided by the operation itself, if at all
+                let operands: Vec<Scalar<T>> = inputs
+                    .iter()
+                    .map(|input| Scalar { data: Shared::clone(input) })
+                    .collect();
+                let grads: Vec<Scalar<T>> = match op.backward_graph(&operands, &out, &grad) {
+                    Some(grads) => grads,
+                    None => panic!(
+                        "{} does not implement CustomOp::backward_graph, so its gradient cannot be differentiated again",
+                        op.name()
+                    ),
+                };
+                assert_eq!(grads.len(), inputs.len(), "{} must return one gradient per input", op.name());
+
+                for (input, input_grad) in inputs.iter().zip(grads) {
+                    Data::accumulate_graph(input, input_grad);
                 }
             }
 
+            Some(Dependency::Select { mask, a, b }) => {
+                // f'(a) = 1 if m != 0, f'(b) = 1 if m = 0
+                let selected = if mask.borrow().val != T::zero() { a } else { b };
+                Data::accumulate_graph(&selected, grad);
+            }
+
+            Some(Dependency::Single { prev, activation }) => {
+                let x = Scalar { data: Shared::clone(&prev) };
+                // Generic floats cannot be on the left hand side of an operator, so 1 - x is written as one - x
+                let one = Scalar::new(T::one(), false);
+
+                let prev_grad = match activation {
+                    // f(x) = tanh(x), f'(x) = 1 - tanh(x)^2
+                    Activation::Tanh => &grad * &(&one - &(&out * &out)),
+                    // f(x) = e^x, f'(x) = e^x
+                    Activation::Exp => &grad * &out,
+                    // f(x) = sigmoid(x), f'(x) = f(x) * (1 - f(x))
+                    Activation::Sigmoid => &grad * &(&out * &(&one - &out)),
+                    // f(x) = max(0, x), f'(x) = 1 if x > 0, 0 otherwise
+                    Activation::ReLU => &grad * (if out.val() > T::zero() { T::one() } else { T::zero() }),
+                    // f(x) = x, with the derivative taken to be 0
+                    Activation::StopGradient => {
+                        return;
+                    }
+                    // f(x) = ln(x), f'(x) = 1 / x
+                    Activation::Log => &grad / &x,
+                    // f(x) = sqrt(x), f'(x) = 1 / (2 * sqrt(x))
+                    Activation::Sqrt => &grad / &(&out * T::from_f64(2.0)),
+                    // f(x) = |x|, f'(x) = sign(x)
+                    Activation::Abs => {
+                        &grad * (if x.val() > T::zero() { T::one() } else if x.val() < T::zero() { -T::one() } else { T::zero() })
+                    }
+                    // f(x) = sin(x), f'(x) = cos(x)
+                    Activation::Sin => &grad * &x.cos(),
+                    // f(x) = cos(x), f'(x) = -sin(x)
+                    Activation::Cos => &(&grad * &x.sin()) * -T::one(),
+                    // f(x) = ln(1 + e^x), f'(x) = sigmoid(x)
+                    Activation::Softplus => &grad * &x.sigmoid(),
+                    // f'(x) = 1 if x > 0, slope otherwise
+                    Activation::LeakyReLU(slope) => &grad * (if x.val() > T::zero() { T::one() } else { slope }),
+                    // f'(x) = 1 if x > 0, f(x) + alpha otherwise
+                    Activation::Elu(alpha) => {
+                        if x.val() > T::zero() { grad } else { &grad * &(&out + alpha) }
+                    }
+                    // f'(x) = 0.5 * (1 + tanh(u)) + 0.5 * x * (1 - tanh(u)^2) * k * (1 + 3 * c * x^2)
+                    Activation::Gelu => {
+                        let x2 = &x * &x;
+                        let (k, c) = (T::from_f64(GELU_K), T::from_f64(GELU_C));
+                        let t = (&(&x + &(&(&x2 * &x) * c)) * k).tanh();
+                        let lhs = &(&t + T::one()) * T::from_f64(0.5);
+                        let rhs = &(&(&(&x * T::from_f64(0.5)) * &(&one - &(&t * &t))) * k) * &(&(&x2 * (T::from_f64(3.0) * c)) + T::one());
+                        &grad * &(&lhs + &rhs)
+                    }
+                    // f'(x) = sigmoid(x) * (1 + x * (1 - sigmoid(x)))
+                    Activation::SiLU => {
+                        let s = x.sigmoid();
+                        &grad * &(&s * &(&(&x * &(&one - &s)) + T::one()))
+                    }
+                    // f(x) = x^p, f'(x) = p * x^(p - 1), and 0 for p = 0
+                    Activation::Pow(power) => {
+                        if power == T::zero() { &grad * T::zero() } else { &grad * &(&x.pow(power - T::one()) * power) }
+                    }
+                    // f'(x) = 1 if lo <= x <= hi, 0 otherwise
+                    Activation::Clamp(lo, hi) => {
+                        &grad * (if x.val() >= lo && x.val() <= hi { T::one() } else { T::zero() })
+                    }
+                    // f'(x) = 1 if -1 < x < 1, 0 otherwise
+                    Activation::HardTanh => {
+                        &grad * (if x.val() > -T::one() && x.val() < T::one() { T::one() } else { T::zero() })
+                    }
+                };
+
+                Data::accumulate_graph(&prev, prev_grad);
+            }
+
             None => (),
         }
     }
 }
 
-fn rc_2_str(rc: Rc<RefCell<Data>>) -> String {
-    let hex_string = format!("{:p}", Rc::as_ptr(&rc));
-    let clean_hex_string = hex_string.trim_start_matches("0x").to_uppercase();
-    // ADD NODE_ at the beginning
-    format!("NODE_{}", clean_hex_string)
+impl<T: Float> Dependency<T> {
+    // Name of the operation that produced the node
+    pub fn name(&self) -> String {
+        match self {
+            Dependency::Single { activation, .. } => activation.to_string(),
+            Dependency::Double { op, .. } => op.to_string(),
+            Dependency::Reduce { reduction, .. } => reduction.to_string(),
+            Dependency::Custom { op, .. } => op.name(),
+            Dependency::Select { .. } => String::from("select"),
+        }
+    }
+
+    // The operands of the node, in order
+    pub fn parents(&self) -> Vec<Shared<Lock<Data<T>>>> {
+        match self {
+            Dependency::Single { prev, .. } => vec![Shared::clone(prev)],
+            Dependency::Double { lhs, rhs, .. } => vec![Shared::clone(lhs), Shared::clone(rhs)],
+            Dependency::Reduce { inputs, .. } | Dependency::Custom { inputs, .. } => {
+                inputs.iter().map(Shared::clone).collect()
+            }
+            Dependency::Select { mask, a, b } => vec![Shared::clone(mask), Shared::clone(a), Shared::clone(b)],
+        }
+    }
+
+    // Moves the parents out of the dependency
+    fn into_parents(self, parents: &mut Vec<Shared<Lock<Data<T>>>>) {
+        match self {
+            Dependency::Single { prev, .. } => parents.push(prev),
+            Dependency::Double { lhs, rhs, .. } => {
+                parents.push(lhs);
+                parents.push(rhs);
+            }
+            Dependency::Reduce { inputs, .. } => parents.extend(inputs),
+            Dependency::Custom { inputs, .. } => parents.extend(inputs),
+            Dependency::Select { mask, a, b } => parents.extend([mask, a, b]),
+        }
+    }
+}
+
+impl<T: Float> Drop for Data<T> {
+    fn drop(&mut self) {
+        // The default drop would recurse once per node through the dependencies,
+        // overflowing the stack on long graphs. Instead, unlink the parents iteratively
+        // and only tear down the ones this node was the last owner of.
+        let mut pending: Vec<Shared<Lock<Data<T>>>> = Vec::new();
+
+        if let Some(dep) = self.dep.take() {
+            dep.into_parents(&mut pending);
+        }
+
+        while let Some(rc) = pending.pop() {
+            if let Ok(cell) = Shared::try_unwrap(rc) {
+                if let Some(dep) = cell.into_inner().dep.take() {
+                    dep.into_parents(&mut pending);
+                }
+            }
+        }
+    }
 }
 
-impl Scalar {
-    pub fn new(value: f32, requires_grad: bool) -> Self {
+impl<T: Float> Scalar<T> {
+    pub fn new(value: T, requires_grad: bool) -> Self {
         Self {
-            data: Rc::new(
-                RefCell::new(Data {
+            data: Shared::new(
+                Lock::new(Data {
                     val: value,
-                    grad: 0.0,
+                    grad: T::zero(),
+                    grad_graph: None,
+                    hooks: Vec::new(),
                     dep: None,
                     requires_grad: requires_grad,
+                    released: false,
+                    tangent: None,
                 })
             ),
         }
     }
 
-    pub fn val(self: &Scalar) -> f32 {
+    pub fn val(self: &Scalar<T>) -> T {
         self.data.borrow().val
     }
 
-    pub fn grad(self: &Scalar) -> f32 {
+    pub fn grad(self: &Scalar<T>) -> T {
         self.data.borrow().grad
     }
 
-    fn topological(
-        data: Rc<RefCell<Data>>,
-        visited: &mut HashSet<usize>,
-        stack: &mut Vec<Rc<RefCell<Data>>>
-    ) {
-        let hash: usize = Data::hash(Rc::clone(&data));
+    pub fn requires_grad(self: &Scalar<T>) -> bool {
+        self.data.borrow().requires_grad
+    }
+
+    // Derivative of the value along the direction set on the leaves, None when no tangent reaches it
+    pub fn tangent(self: &Scalar<T>) -> Option<T> {
+        self.data.borrow().tangent
+    }
 
-        if data.borrow().requires_grad && !visited.contains(&hash) {
-            // Insert the node into the visited set
-            visited.insert(hash);
+    // Makes the scalar an input of forward mode: every result computed from it carries a tangent
+    pub fn set_tangent(self: &Scalar<T>, tangent: T) {
+        self.data.borrow_mut().tangent = Some(tangent);
+    }
 
-            match &data.borrow().dep {
-                Some(Dependency::Single { prev, activation }) => {
-                    // println!("{} -> {} [label=\"{}\"];", rc_2_str(Rc::clone(&data)), rc_2_str(Rc::clone(prev)), activation);
-                    Self::topological(Rc::clone(prev), visited, stack);
-                }
-                Some(Dependency::Double { lhs, rhs, op }) => {
-                    // println!("{} -> {} [label=\"{}\"];", rc_2_str(Rc::clone(&data)), rc_2_str(Rc::clone(lhs)), op);
-                    Self::topological(Rc::clone(lhs), visited, stack);
-                    // println!("{} -> {} [label=\"{}\"];", rc_2_str(Rc::clone(&data)), rc_2_str(Rc::clone(rhs)), op);
-                    Self::topological(Rc::clone(rhs), visited, stack);
+    // A leaf with the same value, cut from the graph
+    pub fn detach(self: &Scalar<T>) -> Scalar<T> {
+        Scalar::new(self.val(), false)
+    }
+
+    // Passes the value through, but sends no gradient back to self
+    pub fn stop_gradient(self: &Scalar<T>) -> Scalar<T> {
+        self.unary(Activation::StopGradient)
+    }
+
+    // Registers a closure that inspects or replaces the incoming gradient, see Hook
+    pub fn register_hook(self: &Scalar<T>, hook: impl Fn(T) -> T + MaybeSync + 'static) {
+        Data::register_hook(&self.data, hook);
+    }
+
+    // Resets grad and grad_graph, see Data::zero_grad
+    pub fn zero_grad(self: &Scalar<T>) {
+        Data::zero_grad(&self.data);
+    }
+
+    // The differentiable gradient computed by Scalar::backward_create_graph, if any
+    pub fn grad_graph(self: &Scalar<T>) -> Option<Scalar<T>> {
+        self.data.borrow().grad_graph.clone()
+    }
+
+    // Wraps the result of an activation applied to self
+    pub(crate) fn unary(&self, activation: Activation<T>) -> Self {
+        let val = activation.apply(self.val());
+        Self::from_op(val, Dependency::Single { prev: Shared::clone(&self.data), activation })
+    }
+
+    // Builds the node holding the result of an operation. The result is differentiable as soon as one of the
+    // operands is, operations between constants are folded into a plain leaf with no dependency.
+    // When an operand carries a tangent, so does the result.
+    pub(crate) fn from_op(val: T, dep: Dependency<T>) -> Self {
+        let operands = dep.parents();
+        anomaly::check_forward(val, &dep.name(), &operands);
+
+        let requires_grad: bool =
+            is_grad_enabled() && operands.iter().any(|operand| operand.borrow().requires_grad);
+        let forward: bool = operands.iter().any(|operand| operand.borrow().tangent.is_some());
+
+        let ans = Scalar::new(val, requires_grad);
+        ans.data.borrow_mut().dep = Some(dep);
+
+        if forward {
+            // The tangent is the sum over the operands of d(ans)/d(operand) · tangent(operand),
+            // with the partial derivatives of the backward pass
+            let mut tangent = T::zero();
+            Data::propagate(&ans.data, T::one(), &mut |operand, partial| {
+                if let Some(operand_tangent) = operand.borrow().tangent {
+                    tangent += partial * operand_tangent;
                 }
-                None => (),
-            }
+            });
+            ans.data.borrow_mut().tangent = Some(tangent);
+        }
 
-            // Push the node onto the stack.
-            // Make sure to do this operation after the recursive calls.
-            stack.push(Rc::clone(&data));
+        if !requires_grad {
+            ans.data.borrow_mut().dep = None;
         }
+        ans
     }
 
-    pub fn backward(self: &Scalar) -> Vec<Rc<RefCell<Data>>> {
-        // Base strutures to sort the nodes topologically
-        let mut visited: HashSet<usize> = HashSet::new();
-        let mut stack: Vec<Rc<RefCell<Data>>> = Vec::new();
+    // Frees the graph once the gradients are propagated, see Scalar::backward_retain_graph to keep it
+    pub fn backward(self: &Scalar<T>) -> Vec<Shared<Lock<Data<T>>>> {
+        Self::backward_impl(std::slice::from_ref(self), &[T::one()], false, false)
+    }
 
-        // Sort the nodes topologically
-        Self::topological(Rc::clone(&self.data), &mut visited, &mut stack);
+    // Like Scalar::backward, but keeps the graph, e.g. to backpropagate through it again or to export it
+    pub fn backward_retain_graph(self: &Scalar<T>) -> Vec<Shared<Lock<Data<T>>>> {
+        Self::backward_impl(std::slice::from_ref(self), &[T::one()], false, true)
+    }
 
-        // Reverse the stack, since we want to backpropagate from the output to the input
-        stack.reverse();
+    // Like Scalar::backward, but also stores the gradients as Scalars that are themselves
+    // part of a graph (see Scalar::grad_graph), e.g. to compute second order derivatives.
+    // Those gradients depend on the graph, so it is always retained.
+    pub fn backward_create_graph(self: &Scalar<T>) -> Vec<Shared<Lock<Data<T>>>> {
+        Self::backward_impl(std::slice::from_ref(self), &[T::one()], true, true)
+    }
 
-        // Set the gradient of the output to 1.0
-        self.data.borrow_mut().grad = 1.0;
+    // Backpropagates from several roots in a single pass, each seeded with its own output gradient.
+    // This computes the vector-Jacobian product of the seeds with the roots.
+    pub fn backward_many(roots: &[Scalar<T>], seeds: &[T], retain_graph: bool) -> Vec<Shared<Lock<Data<T>>>> {
+        Self::backward_impl(roots, seeds, false, retain_graph)
+    }
 
-        // Backpropagate the gradient
-        for node in &stack {
-            // println!("{} [label=\"V:{:.2}\\nG: {:.2}\\nRG={}\"];", rc_2_str(Rc::clone(node)), node.borrow().val, node.borrow().grad, node.borrow().requires_grad);
-            Data::backward(Rc::clone(node));
+    // The nodes reachable from the roots that require grad, sorted topologically from the roots to the leaves.
+    // This is the order the backward pass visits them in, the nodes shared by several roots appear once.
+    pub fn topological_order(roots: &[Scalar<T>]) -> Vec<Shared<Lock<Data<T>>>> {
+        topological_sort(roots.iter().map(|root| Shared::clone(&root.data)).collect())
+    }
+
+    fn backward_impl(
+        roots: &[Scalar<T>],
+        seeds: &[T],
+        create_graph: bool,
+        retain_graph: bool
+    ) -> Vec<Shared<Lock<Data<T>>>> {
+        assert_eq!(roots.len(), seeds.len(), "One seed per root is required");
+
+        let stack = Self::topological_order(roots);
+        Self::backward_order(&stack, roots, seeds, create_graph);
+
+        // Drop the links to the operands, so that the graph is freed even if the caller keeps the outputs around.
+        // The nodes themselves stay alive only as long as they are referenced, e.g. by the returned order.
+        if !retain_graph {
+            for node in &stack {
+                let mut data = node.borrow_mut();
+                if data.dep.is_some() {
+                    data.dep = None;
+                    data.released = true;
+                }
+            }
         }
 
         stack
     }
+
+    // Backpropagates from the roots over an order computed beforehand by Scalar::topological_order,
+    // e.g. to run the backward pass of a CompiledGraph without sorting the nodes again.
+    pub fn backward_order(order: &[Shared<Lock<Data<T>>>], roots: &[Scalar<T>], seeds: &[T], create_graph: bool) {
+        assert_eq!(roots.len(), seeds.len(), "One seed per root is required");
+
+        // Interior gradients only make sense within a single pass, so they start from zero.
+        // The leaves get the gradient of the pass aside, see Data::backward, and add it to their own at the end.
+        for node in order {
+            let mut data = node.borrow_mut();
+            if data.dep.is_some() {
+                data.grad = T::zero();
+                data.grad_graph = None;
+            }
+        }
+        let mut leaves: HashMap<usize, T> = HashMap::new();
+
+        // Seed the outputs.
+        // A root can also be reached from another root, so its seed is added on top.
+        for (root, seed) in roots.iter().zip(seeds.iter()) {
+            let mut data = root.data.borrow_mut();
+            if data.accumulates() {
+                *leaves.entry(Data::hash(Shared::clone(&root.data))).or_insert(T::zero()) += *seed;
+            } else {
+                data.grad += *seed;
+            }
+        }
+        if create_graph {
+            for (root, seed) in roots.iter().zip(seeds.iter()) {
+                Data::accumulate_graph(&root.data, Scalar::new(*seed, false));
+            }
+        }
+
+        // Backpropagate the gradient
+        for node in order {
+            Data::backward(node, &mut leaves);
+            if create_graph {
+                Data::backward_graph(Shared::clone(node));
+                // Once handed to the parents, the differentiable gradient of an interior node is not needed anymore.
+                // It usually refers back to the node, so keeping it would leak the graph through a reference cycle.
+                let interior = node.borrow().dep.is_some();
+                if interior {
+                    let grad_graph = node.borrow_mut().grad_graph.take();
+                    drop(grad_graph);
+                }
+            }
+        }
+
+        for node in order {
+            let mut data = node.borrow_mut();
+            if data.accumulates() {
+                if let Some(grad) = leaves.get(&Data::hash(Shared::clone(node))) {
+                    data.grad += *grad;
+                }
+            }
+        }
+    }
 }
 
-impl Nonlinear for Scalar {
+// Functional gradient: returns d(outputs)/d(inputs) as new constant Scalars, leaving every grad in the graph untouched.
+// Each output is seeded with the matching entry of `seeds`, or 1.0 when no seeds are given.
+pub fn grad<T: Float>(outputs: &[Scalar<T>], seeds: Option<&[T]>, inputs: &[Scalar<T>]) -> Vec<Scalar<T>> {
+    if let Some(seeds) = seeds {
+        assert_eq!(outputs.len(), seeds.len(), "One seed per output is required");
+    }
+
+    let stack = Scalar::topological_order(outputs);
+
+    // Gradients are kept aside, indexed by node
+    let mut grads: HashMap<usize, T> = HashMap::new();
+
+    for (i, output) in outputs.iter().enumerate() {
+        let seed = seeds.map_or(T::one(), |seeds| seeds[i]);
+        *grads.entry(Data::hash(Shared::clone(&output.data))).or_insert(T::zero()) += seed;
+    }
+
+    for node in &stack {
+        let grad = grads.get(&Data::hash(Shared::clone(node))).copied().unwrap_or(T::zero());
+        let grad = Data::apply_hooks(node, grad);
+        grads.insert(Data::hash(Shared::clone(node)), grad);
+
+        Data::propagate(node, grad, &mut |parent, contribution| {
+            *grads.entry(Data::hash(Shared::clone(parent))).or_insert(T::zero()) += contribution;
+        });
+    }
+
+    inputs
+        .iter()
+        .map(|input| {
+            let grad = match input.data.borrow().requires_grad {
+                true => grads.get(&Data::hash(Shared::clone(&input.data))).copied().unwrap_or(T::zero()),
+                false => T::zero(),
+            };
+            Scalar::new(grad, false)
+        })
+        .collect()
+}
+
+// Forward mode: calls f on new leaves holding the values of the inputs, each carrying its tangent, and returns
+// the outputs of f along with the Jacobian-vector product d(outputs)/d(inputs) · tangents as new constant Scalars.
+// The tangents are computed together with the values, with the rules of the backward pass, so the cost does not
+// depend on the number of outputs. f runs under no_grad: no graph is recorded and no grad is touched.
+pub fn jvp<T: Float>(
+    f: impl FnOnce(&[Scalar<T>]) -> Vec<Scalar<T>>,
+    inputs: &[Scalar<T>],
+    tangents: &[T]
+) -> (Vec<Scalar<T>>, Vec<Scalar<T>>) {
+    assert_eq!(inputs.len(), tangents.len(), "One tangent per input is required");
+
+    let leaves: Vec<Scalar<T>> = inputs
+        .iter()
+        .zip(tangents.iter())
+        .map(|(input, tangent)| {
+            let leaf = Scalar::new(input.val(), false);
+            leaf.set_tangent(*tangent);
+            leaf
+        })
+        .collect();
+
+    let outputs = no_grad(|| f(&leaves));
+    let tangents = outputs
+        .iter()
+        .map(|output| Scalar::new(output.tangent().unwrap_or(T::zero()), false))
+        .collect();
+
+    (outputs, tangents)
+}
+
+impl<T: Float> Nonlinear<T> for Scalar<T> {
     fn tanh(&self) -> Self {
-        Self {
-            data: Rc::new(
-                RefCell::new(Data {
-                    val: self.data.borrow().val.tanh(),
-                    grad: 0.0,
-                    dep: Some(Dependency::Single {
-                        prev: Rc::clone(&self.data),
-                        activation: Activation::Tanh,
-                    }),
-                    requires_grad: self.data.borrow().requires_grad,
-                })
-            ),
-        }
+        self.unary(Activation::Tanh)
     }
     fn exp(&self) -> Self {
-        Self {
-            data: Rc::new(
-                RefCell::new(Data {
-                    val: self.data.borrow().val.exp(),
-                    grad: 0.0,
-                    dep: Some(Dependency::Single {
-                        prev: Rc::clone(&self.data),
-                        activation: Activation::Exp,
-                    }),
-                    requires_grad: self.data.borrow().requires_grad,
-                })
-            ),
-        }
+        self.unary(Activation::Exp)
     }
     fn sigmoid(&self) -> Self {
-        Self {
-            data: Rc::new(
-                RefCell::new(Data {
-                    val: 1.0 / (1.0 + (-self.data.borrow().val).exp()),
-                    grad: 0.0,
-                    dep: Some(Dependency::Single {
-                        prev: Rc::clone(&self.data),
-                        activation: Activation::Sigmoid,
-                    }),
-                    requires_grad: self.data.borrow().requires_grad,
-                })
-            ),
-        }
+        self.unary(Activation::Sigmoid)
     }
     fn relu(&self) -> Self {
-        let val = {
-            if self.data.borrow().val > 0.0 { self.data.borrow().val } else { 0.0 }
+        self.unary(Activation::ReLU)
+    }
+    fn log(&self) -> Self {
+        self.unary(Activation::Log)
+    }
+    fn sqrt(&self) -> Self {
+        self.unary(Activation::Sqrt)
+    }
+    fn abs(&self) -> Self {
+        self.unary(Activation::Abs)
+    }
+    fn sin(&self) -> Self {
+        self.unary(Activation::Sin)
+    }
+    fn cos(&self) -> Self {
+        self.unary(Activation::Cos)
+    }
+    fn softplus(&self) -> Self {
+        self.unary(Activation::Softplus)
+    }
+    fn leaky_relu(&self, slope: T) -> Self {
+        self.unary(Activation::LeakyReLU(slope))
+    }
+    fn elu(&self, alpha: T) -> Self {
+        self.unary(Activation::Elu(alpha))
+    }
+    fn gelu(&self) -> Self {
+        self.unary(Activation::Gelu)
+    }
+    fn silu(&self) -> Self {
+        self.unary(Activation::SiLU)
+    }
+    fn hard_tanh(&self) -> Self {
+        self.unary(Activation::HardTanh)
+    }
+    fn clamp(&self, lo: T, hi: T) -> Self {
+        self.unary(Activation::Clamp(lo, hi))
+    }
+    fn pow(&self, power: T) -> Self {
+        self.unary(Activation::Pow(power))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_grad_records_nothing() {
+        let x = Scalar::new(2.0, true);
+
+        let y = no_grad(|| {
+            // Nested scopes restore the state of the outer one
+            no_grad(|| ());
+            assert!(!is_grad_enabled());
+            (&x * &x).exp()
+        });
+        assert!(is_grad_enabled());
+        assert!(y.data.borrow().dep.is_none());
+        assert!(!y.requires_grad());
+        assert_eq!(y.val(), (4.0f32).exp());
+
+        // Outside the scope the graph is recorded again
+        assert!((&x * &x).data.borrow().dep.is_some());
+    }
+
+    #[test]
+    fn jvp_matches_finite_differences() {
+        let f = |x: &[Scalar<f64>]| -> Vec<Scalar<f64>> {
+            vec![(&x[0] * &x[1].sin()).gelu(), Scalar::dot(x, x).sqrt(), x[0].max(&x[1]).exp()]
         };
-        Self {
-            data: Rc::new(
-                RefCell::new(Data {
-                    val,
-                    grad: 0.0,
-                    dep: Some(Dependency::Single {
-                        prev: Rc::clone(&self.data),
-                        activation: Activation::ReLU,
-                    }),
-                    requires_grad: self.data.borrow().requires_grad,
-                })
-            ),
+        let (x, v) = ([0.3, -1.2], [0.7, -1.3]);
+        let inputs: Vec<Scalar<f64>> = x.iter().map(|&x| Scalar::new(x, true)).collect();
+
+        let (outputs, tangents) = jvp(f, &inputs, &v);
+
+        // Central differences along v
+        let eps = 1e-6;
+        let at = |sign: f64| f(&[Scalar::new(x[0] + sign * eps * v[0], false), Scalar::new(x[1] + sign * eps * v[1], false)]);
+        for (k, (plus, minus)) in at(1.0).iter().zip(at(-1.0).iter()).enumerate() {
+            assert!((tangents[k].val() - (plus.val() - minus.val()) / (2.0 * eps)).abs() < 1e-6);
+            assert!(outputs[k].data.borrow().dep.is_none());
         }
+        assert!(inputs.iter().all(|input| input.grad() == 0.0 && input.tangent().is_none()));
     }
-    fn pow(&self, power: usize) -> Self {
-        let mut ans = self.clone();
-        for _ in 1..power as usize {
-            ans = &ans * &self.clone();
+
+    #[test]
+    fn backward_retain_graph_keeps_the_graph() {
+        let x = Scalar::new(2.0, true);
+        let h = x.tanh();
+        let y = &h * &h;
+
+        y.backward_retain_graph();
+        y.backward();
+        assert!((x.grad() - 2.0 * 2.0 * h.val() * (1.0 - h.val() * h.val())).abs() < 1e-6);
+
+        // The interior nodes are released, the leaves are not
+        assert!(y.data.borrow().dep.is_none() && h.data.borrow().released);
+        assert!(!x.data.borrow().released);
+    }
+
+    #[test]
+    #[should_panic(expected = "freed by a previous backward pass")]
+    fn backward_through_a_freed_graph_panics() {
+        let x = Scalar::new(2.0, true);
+        let h = x.tanh();
+        (&h * 2.0).backward();
+        (&h * 3.0).backward();
+    }
+
+    #[test]
+    fn functional_grad_leaves_grads_untouched() {
+        let x = Scalar::new(3.0, true);
+        x.data.borrow_mut().grad = 5.0;
+        let y = &(&x * &x) * &x;
+
+        let dx = grad(std::slice::from_ref(&y), None, std::slice::from_ref(&x));
+        assert_eq!(dx[0].val(), 27.0);
+        assert!(!dx[0].requires_grad());
+        assert_eq!(x.grad(), 5.0);
+        assert_eq!(y.grad(), 0.0);
+
+        // The seeds scale the output gradient, and the graph is still there
+        let dx = grad(std::slice::from_ref(&y), Some(&[2.0]), std::slice::from_ref(&x));
+        assert_eq!(dx[0].val(), 54.0);
+        y.backward();
+        assert_eq!(x.grad(), 32.0);
+    }
+
+    #[test]
+    fn hooks_replace_gradients() {
+        let (x, y) = (Scalar::new(2.0, true), Scalar::new(3.0, true));
+        let z = &x * &y;
+
+        // Hooks run in registration order, and the parents receive the replaced gradient
+        z.register_hook(|grad| grad + 1.0);
+        z.register_hook(|grad| grad * 10.0);
+        // A leaf keeps what its hooks return
+        y.register_hook(|_| 0.0);
+
+        z.exp().backward();
+        let dz = (6.0f32).exp();
+        assert_eq!(z.grad(), (dz + 1.0) * 10.0);
+        assert_eq!(x.grad(), (dz + 1.0) * 10.0 * 3.0);
+        assert_eq!(y.grad(), 0.0);
+    }
+
+    #[test]
+    fn detach_and_stop_gradient_cut_the_graph() {
+        let x = Scalar::new(3.0, true);
+        let y = &x * &x;
+
+        let detached = y.detach();
+        assert_eq!(detached.val(), 9.0);
+        assert!(!detached.requires_grad());
+        assert!(detached.data.borrow().dep.is_none());
+
+        // Only the path that does not go through stop_gradient contributes: d(x * stop(x^2))/dx = x^2
+        let stopped = y.stop_gradient();
+        assert_eq!(stopped.val(), 9.0);
+        (&x * &stopped).backward();
+        assert_eq!(x.grad(), 9.0);
+    }
+
+    #[test]
+    fn nonlinear_functions_record_their_activation() {
+        let x = Scalar::new(0.5, true);
+        let cases: Vec<(Scalar, &str)> = vec![
+            (x.exp(), "exp"),
+            (x.tanh(), "tanh"),
+            (x.sigmoid(), "sigmoid"),
+            (x.relu(), "relu"),
+            (x.pow(3.0), "pow(3)"),
+            (x.log(), "log"),
+            (x.sqrt(), "sqrt"),
+            (x.abs(), "abs"),
+            (x.sin(), "sin"),
+            (x.cos(), "cos"),
+            (x.softplus(), "softplus"),
+            (x.leaky_relu(0.1), "leaky_relu(0.1)"),
+            (x.elu(1.5), "elu(1.5)"),
+            (x.gelu(), "gelu"),
+            (x.silu(), "silu"),
+            (x.hard_tanh(), "hard_tanh"),
+            (x.clamp(-1.0, 1.0), "clamp(-1, 1)"),
+        ];
+
+        for (y, name) in cases {
+            assert_eq!(y.data.borrow().dep.as_ref().map(|dep| dep.name()), Some(String::from(name)));
         }
-        ans
     }
 }
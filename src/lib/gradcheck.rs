@@ -0,0 +1,448 @@
+// A tool for the users of the library, the demo binary itself never checks its gradients
+#![cfg_attr(not(test), allow(dead_code))]
+
+use crate::lib::dense::Tensor;
+use crate::lib::float::Float;
+use crate::lib::grad::no_grad;
+use crate::lib::grad::Scalar;
+use crate::lib::tensor::Tensor2D;
+
+// Result of a gradient check, indexed as [input][row][col]
+pub struct GradCheck<T: Float = f32> {
+    // Gradients computed by Scalar::backward
+    pub analytic: Vec<Vec<Vec<T>>>,
+    // Gradients estimated with central differences
+    pub numeric: Vec<Vec<Vec<T>>>,
+    // Error between the two, see relative_error
+    pub errors: Vec<Vec<Vec<T>>>,
+    // Worst error for each input, with the position of the element
+    pub worst: Vec<(T, usize, usize)>,
+}
+
+impl<T: Float> GradCheck<T> {
+    // Worst error across all the inputs, NaN if any gradient is NaN
+    pub fn max_error(&self) -> T {
+        self.worst.iter().fold(T::zero(), |max: T, (error, _, _)| {
+            if error.is_nan() || *error > max { *error } else { max }
+        })
+    }
+
+    pub fn passed(&self, tolerance: T) -> bool {
+        self.max_error() <= tolerance
+    }
+
+    // Adds the gradients of the next input, along with their errors
+    fn push(&mut self, analytic: Vec<Vec<T>>, numeric: Vec<Vec<T>>) {
+        let mut worst: (T, usize, usize) = (T::zero(), 0, 0);
+
+        let errors: Vec<Vec<T>> = analytic
+            .iter()
+            .zip(numeric.iter())
+            .enumerate()
+            .map(|(row, (analytic_row, numeric_row))| {
+                analytic_row
+                    .iter()
+                    .zip(numeric_row.iter())
+                    .enumerate()
+                    .map(|(col, (analytic, numeric))| {
+                        let error = relative_error(*analytic, *numeric);
+                        if error > worst.0 || error.is_nan() {
+                            worst = (error, row, col);
+                        }
+                        error
+                    })
+                    .collect()
+            })
+            .collect();
+
+        self.analytic.push(analytic);
+        self.numeric.push(numeric);
+        self.errors.push(errors);
+        self.worst.push(worst);
+    }
+}
+
+// Relative error, falling back to the absolute one for gradients smaller than 1.0
+// so that values close to zero do not blow it up.
+fn relative_error<T: Float>(analytic: T, numeric: T) -> T {
+    (analytic - numeric).abs() / analytic.abs().max(numeric.abs()).max(T::one())
+}
+
+// Compares the gradients of `f` computed by the backward pass with central differences:
+// every element of every input is moved by +-eps, and the change of the output is measured.
+// The inputs are copied into fresh leaves, so their values and gradients are left untouched.
+pub fn gradcheck<T: Float>(f: impl Fn(&[Tensor2D<T>]) -> Scalar<T>, inputs: &[Tensor2D<T>], eps: T) -> GradCheck<T> {
+    let leaves: Vec<Tensor2D<T>> = inputs
+        .iter()
+        .map(|input| {
+            let leaf = Tensor2D::zeros(input.rows, input.cols, true);
+            for row in 0..input.rows {
+                for col in 0..input.cols {
+                    leaf.data[row][col].data.borrow_mut().val = input.data[row][col].val();
+                }
+            }
+            leaf
+        })
+        .collect();
+
+    // Analytic gradients
+    f(&leaves).backward();
+
+    let mut check = GradCheck {
+        analytic: Vec::new(),
+        numeric: Vec::new(),
+        errors: Vec::new(),
+        worst: Vec::new(),
+    };
+
+    for leaf in leaves.iter() {
+        let (mut analytic, mut numeric) = (Vec::new(), Vec::new());
+
+        for row in 0..leaf.rows {
+            let (mut analytic_row, mut numeric_row) = (Vec::new(), Vec::new());
+
+            for col in 0..leaf.cols {
+                let element = &leaf.data[row][col];
+                let val = element.val();
+
+                // Numeric gradient, no need to build the graph for these evaluations
+                let eval = |val: T| {
+                    element.data.borrow_mut().val = val;
+                    no_grad(|| f(&leaves).val())
+                };
+                let (plus, minus) = (eval(val + eps), eval(val - eps));
+                element.data.borrow_mut().val = val;
+
+                analytic_row.push(element.grad());
+                numeric_row.push((plus - minus) / (T::from_f64(2.0) * eps));
+            }
+
+            analytic.push(analytic_row);
+            numeric.push(numeric_row);
+        }
+
+        check.push(analytic, numeric);
+    }
+
+    check
+}
+
+// Same as gradcheck, for functions of dense tensors returning a tensor with a single element.
+// The leading dimensions of an input are flattened into the rows of the results, its last dimension
+// gives the columns, and an input of rank 0 is a single element.
+pub fn gradcheck_tensor<T: Float>(f: impl Fn(&[Tensor<T>]) -> Tensor<T>, inputs: &[Tensor<T>], eps: T) -> GradCheck<T> {
+    let leaves: Vec<Tensor<T>> = inputs
+        .iter()
+        .map(|input| Tensor::new(input.vals(), &input.shape(), true))
+        .collect();
+
+    // Analytic gradients
+    f(&leaves).backward();
+
+    let mut check = GradCheck {
+        analytic: Vec::new(),
+        numeric: Vec::new(),
+        errors: Vec::new(),
+        worst: Vec::new(),
+    };
+
+    for leaf in leaves.iter() {
+        let cols = leaf.shape().last().copied().unwrap_or(1);
+        let grads = leaf.grads();
+        let mut numeric: Vec<T> = Vec::with_capacity(grads.len());
+
+        // The leaves are contiguous, so the elements are visited in row-major order
+        for i in 0..grads.len() {
+            let val = leaf.data.borrow().vals[i];
+
+            // Numeric gradient, no need to build the graph for these evaluations
+            let eval = |val: T| {
+                leaf.data.borrow_mut().vals[i] = val;
+                no_grad(|| f(&leaves).item())
+            };
+            let (plus, minus) = (eval(val + eps), eval(val - eps));
+            leaf.data.borrow_mut().vals[i] = val;
+
+            numeric.push((plus - minus) / (T::from_f64(2.0) * eps));
+        }
+
+        check.push(
+            grads.chunks(cols).map(|row| row.to_vec()).collect(),
+            numeric.chunks(cols).map(|row| row.to_vec()).collect()
+        );
+    }
+
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ gradcheck, gradcheck_tensor };
+    use crate::lib::dense::Tensor;
+    use crate::lib::grad::{ Activation, Nonlinear, Scalar };
+    use crate::lib::tensor::Tensor2D;
+
+    const EPS: f64 = 1e-6;
+    const TOLERANCE: f64 = 1e-7;
+
+    // Values away from the kinks of the piecewise rules (0 and +-1)
+    fn mixed() -> Tensor2D<f64> {
+        Tensor2D::row(vec![-1.7, -0.6, -0.2, 0.3, 0.8, 1.9])
+    }
+
+    // Values in the domain of log, sqrt and pow
+    fn positive() -> Tensor2D<f64> {
+        Tensor2D::row(vec![0.3, 0.7, 1.2, 1.6, 2.5, 3.1])
+    }
+
+    // Sum of f applied to the pairs of elements of the two inputs
+    fn pairwise(f: impl Fn(&Scalar<f64>, &Scalar<f64>) -> Scalar<f64>) -> impl Fn(&[Tensor2D<f64>]) -> Scalar<f64> {
+        move |inputs| {
+            let elements: Vec<Scalar<f64>> = inputs[0].data[0]
+                .iter()
+                .zip(inputs[1].data[0].iter())
+                .map(|(x, y)| f(x, y))
+                .collect();
+            Scalar::sum(&elements)
+        }
+    }
+
+    // Sum of f applied to every element of the input, as both operands
+    fn same_operand(f: impl Fn(&Scalar<f64>, &Scalar<f64>) -> Scalar<f64>) -> impl Fn(&[Tensor2D<f64>]) -> Scalar<f64> {
+        move |inputs| {
+            let elements: Vec<Scalar<f64>> = inputs[0].data[0]
+                .iter()
+                .map(|x| f(x, x))
+                .collect();
+            Scalar::sum(&elements)
+        }
+    }
+
+    fn check(name: &str, f: impl Fn(&[Tensor2D<f64>]) -> Scalar<f64>, inputs: &[Tensor2D<f64>]) {
+        let check = gradcheck(f, inputs, EPS);
+        assert!(check.passed(TOLERANCE), "{}: the gradients differ by {}", name, check.max_error());
+    }
+
+    // A dense tensor of the given shape with distinct values of both signs, away from the kinks
+    fn dense(shape: &[usize]) -> Tensor<f64> {
+        let n: usize = shape.iter().product();
+        Tensor::new((0..n).map(|i| (i as f64 * 1.3 + 0.4).sin() * 1.8).collect(), shape, false)
+    }
+
+    // Sum of the elements weighted by distinct constants, so that a gradient sent to the wrong element shows up
+    fn weighted(tensor: &Tensor<f64>) -> Tensor<f64> {
+        let n = tensor.vals().len();
+        let weights = Tensor::new((1..=n).map(|i| i as f64 * 0.25).collect(), &tensor.shape(), false);
+        tensor.mul_elem(&weights).sum()
+    }
+
+    fn check_dense(name: &str, f: impl Fn(&[Tensor<f64>]) -> Tensor<f64>, inputs: &[Tensor<f64>]) {
+        let check = gradcheck_tensor(f, inputs, EPS);
+        assert!(check.passed(TOLERANCE), "{}: the gradients differ by {}", name, check.max_error());
+    }
+
+    #[test]
+    fn activations() {
+        let activations: Vec<(Activation<f64>, Tensor2D<f64>)> = vec![
+            (Activation::Exp, mixed()),
+            (Activation::Tanh, mixed()),
+            (Activation::Sigmoid, mixed()),
+            (Activation::ReLU, mixed()),
+            (Activation::StopGradient, mixed()),
+            (Activation::Log, positive()),
+            (Activation::Sqrt, positive()),
+            (Activation::Abs, mixed()),
+            (Activation::Sin, mixed()),
+            (Activation::Cos, mixed()),
+            (Activation::Softplus, mixed()),
+            (Activation::LeakyReLU(0.1), mixed()),
+            (Activation::Elu(1.5), mixed()),
+            (Activation::Gelu, mixed()),
+            (Activation::SiLU, mixed()),
+            (Activation::HardTanh, mixed()),
+            (Activation::Pow(2.5), positive()),
+            (Activation::Clamp(-1.0, 1.0), mixed()),
+        ];
+
+        for (activation, input) in activations {
+            let name = activation.to_string();
+            // StopGradient is the only rule that does not match its numeric derivative, by design
+            if let Activation::StopGradient = activation {
+                let check = gradcheck(|inputs| Tensor2D::nonlinear(&inputs[0], activation.clone()).sum(), &[input], EPS);
+                assert!(check.analytic[0][0].iter().all(|grad| *grad == 0.0));
+                continue;
+            }
+            check(&name, |inputs| Tensor2D::nonlinear(&inputs[0], activation.clone()).sum(), &[input]);
+        }
+    }
+
+    #[test]
+    fn operations() {
+        let (x, y) = (mixed(), Tensor2D::row(vec![0.9, -1.1, 0.4, -0.5, 1.3, 2.2]));
+
+        check("+", pairwise(|x, y| x + y), &[x.clone(), y.clone()]);
+        check("-", pairwise(|x, y| x - y), &[x.clone(), y.clone()]);
+        check("*", pairwise(|x, y| x * y), &[x.clone(), y.clone()]);
+        check("/", pairwise(|x, y| x / y), &[x.clone(), y.clone()]);
+        check("^", pairwise(|x, y| x ^ y), &[positive(), y.clone()]);
+        check("max", pairwise(|x, y| x.max(y)), &[x.clone(), y.clone()]);
+        check("min", pairwise(|x, y| x.min(y)), &[x, y]);
+    }
+
+    #[test]
+    fn same_operands() {
+        // Both contributions go to the same node and must add up
+        check("x + x", same_operand(|x, y| x + y), &[mixed()]);
+        check("x - x", same_operand(|x, y| x - y), &[mixed()]);
+        check("x * x", same_operand(|x, y| x * y), &[mixed()]);
+        check("x / x", same_operand(|x, y| x / y), &[mixed()]);
+        check("x ^ x", same_operand(|x, y| x ^ y), &[positive()]);
+    }
+
+    #[test]
+    fn reductions() {
+        check("sum", |inputs| &inputs[0].sum() * &inputs[0].sum(), &[mixed()]);
+        check("mean", |inputs| inputs[0].mean().exp(), &[mixed()]);
+        check("dot", |inputs| Scalar::dot(&inputs[0].data[0], &inputs[1].data[0]), &[mixed(), positive()]);
+        check("dot x x", |inputs| Scalar::dot(&inputs[0].data[0], &inputs[0].data[0]), &[mixed()]);
+    }
+
+    #[test]
+    fn dense_activations() {
+        let activations: Vec<Activation<f64>> = vec![
+            Activation::Exp,
+            Activation::Tanh,
+            Activation::Sigmoid,
+            Activation::ReLU,
+            Activation::Abs,
+            Activation::Sin,
+            Activation::Cos,
+            Activation::Softplus,
+            Activation::LeakyReLU(0.1),
+            Activation::Elu(1.5),
+            Activation::Gelu,
+            Activation::SiLU,
+            Activation::HardTanh,
+            Activation::Clamp(-1.0, 1.0),
+        ];
+
+        for activation in activations {
+            let name = activation.to_string();
+            check_dense(&name, |inputs| weighted(&Tensor::nonlinear(&inputs[0], activation.clone())), &[dense(&[2, 3])]);
+        }
+
+        let positive = Tensor::new(positive().data[0].iter().map(|x| x.val()).collect(), &[2, 3], false);
+        check_dense("log", |inputs| weighted(&inputs[0].log()), std::slice::from_ref(&positive));
+        check_dense("sqrt", |inputs| weighted(&inputs[0].sqrt()), std::slice::from_ref(&positive));
+        check_dense("pow", |inputs| weighted(&(&inputs[0] ^ 2.5)), &[positive]);
+    }
+
+    #[test]
+    fn dense_operations() {
+        let (x, y) = (dense(&[2, 3]), Tensor::new(vec![0.9, -1.1, 0.4, -0.5, 1.3, 2.2], &[2, 3], false));
+        let positive = Tensor::new(positive().data[0].iter().map(|x| x.val()).collect(), &[2, 3], false);
+
+        check_dense("+", |inputs| weighted(&(&inputs[0] + &inputs[1])), &[x.clone(), y.clone()]);
+        check_dense("-", |inputs| weighted(&(&inputs[0] - &inputs[1])), &[x.clone(), y.clone()]);
+        check_dense("*", |inputs| weighted(&inputs[0].mul_elem(&inputs[1])), &[x.clone(), y.clone()]);
+        check_dense("/", |inputs| weighted(&inputs[0].div_elem(&inputs[1])), &[x.clone(), y.clone()]);
+        check_dense("^", |inputs| weighted(&(&inputs[0] ^ &inputs[1])), &[positive.clone(), y.clone()]);
+        check_dense("max", |inputs| weighted(&inputs[0].max(&inputs[1])), &[x.clone(), y.clone()]);
+        check_dense("min", |inputs| weighted(&inputs[0].min(&inputs[1])), &[x.clone(), y]);
+
+        // Both contributions go to the same tensor and must add up
+        check_dense("x * x", |inputs| weighted(&inputs[0].mul_elem(&inputs[0])), std::slice::from_ref(&x));
+        check_dense("x / x", |inputs| weighted(&inputs[0].div_elem(&inputs[0])), &[x]);
+        check_dense("x ^ x", |inputs| weighted(&(&inputs[0] ^ &inputs[0])), &[positive]);
+    }
+
+    #[test]
+    fn dense_matmul() {
+        check_dense("matmul", |inputs| weighted(&(&inputs[0] * &inputs[1])), &[dense(&[2, 3]), dense(&[3, 4])]);
+        check_dense("x * x", |inputs| weighted(&(&inputs[0] * &inputs[0])), &[dense(&[3, 3])]);
+        check_dense("x * x^T", |inputs| weighted(&(&inputs[0] * &inputs[0].transpose())), &[dense(&[2, 3])]);
+    }
+
+    #[test]
+    fn dense_reductions() {
+        check_dense("sum", |inputs| inputs[0].sum().mul_elem(&inputs[0].sum()), &[dense(&[2, 3])]);
+        check_dense("mean", |inputs| inputs[0].mean().exp(), &[dense(&[2, 3])]);
+    }
+
+    #[test]
+    fn dense_views() {
+        check_dense("reshape", |inputs| weighted(&inputs[0].reshape(&[3, 2]).tanh()), &[dense(&[2, 3])]);
+        check_dense("permute", |inputs| weighted(&inputs[0].permute(&[2, 0, 1]).tanh()), &[dense(&[2, 3, 4])]);
+        check_dense("index", |inputs| weighted(&inputs[0].at(&[1]).tanh()), &[dense(&[2, 3, 4])]);
+    }
+
+    #[test]
+    fn dense_hooks() {
+        // An identity hook leaves the gradients untouched, one scaling them scales everything upstream
+        let f = |scale: f64| {
+            move |inputs: &[Tensor<f64>]| {
+                let hidden = inputs[0].tanh();
+                hidden.register_hook(move |grad| grad * scale);
+                weighted(&hidden.exp())
+            }
+        };
+        check_dense("identity hook", f(1.0), &[dense(&[2, 3])]);
+
+        let check = gradcheck_tensor(f(3.0), &[dense(&[2, 3])], EPS);
+        for (analytic, numeric) in check.analytic[0].iter().flatten().zip(check.numeric[0].iter().flatten()) {
+            assert!((analytic - 3.0 * numeric).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn dense_ranks() {
+        let x = dense(&[2, 3, 4]);
+
+        check_dense("permute 4d", |inputs| weighted(&inputs[0].permute(&[3, 1, 0, 2]).tanh()), &[dense(&[2, 3, 1, 4])]);
+        check_dense("select 1", |inputs| weighted(&inputs[0].select(1, 2).tanh()), std::slice::from_ref(&x));
+        check_dense("select 2", |inputs| weighted(&inputs[0].select(2, 1).tanh()), std::slice::from_ref(&x));
+        check_dense("at", |inputs| weighted(&inputs[0].at(&[1, 2]).tanh()), std::slice::from_ref(&x));
+        check_dense("at all", |inputs| inputs[0].at(&[1, 0, 3]).exp(), std::slice::from_ref(&x));
+        check_dense("squeeze", |inputs| weighted(&inputs[0].squeeze(1).tanh()), &[dense(&[2, 1, 3])]);
+        check_dense("unsqueeze", |inputs| weighted(&inputs[0].unsqueeze(0).unsqueeze(3).tanh()), &[dense(&[2, 3])]);
+    }
+
+    #[test]
+    fn dense_batched_matmul() {
+        let f = |inputs: &[Tensor<f64>]| weighted(&(&inputs[0] * &inputs[1]).tanh());
+        let (x, w) = (dense(&[3, 2, 4]), dense(&[4, 5]));
+        check_dense("[b, n, k] x [k, m]", f, &[x.clone(), w.clone()]);
+
+        // The weight is shared by the whole batch, so its gradient is the sum of those of every product
+        let batched = gradcheck_tensor(f, &[x.clone(), w.clone()], EPS);
+        let mut summed = [0.0; 20];
+        for b in 0..3 {
+            // The weights that weighted gives to the elements of the b-th product
+            let weights = Tensor::new((b * 10 + 1..=b * 10 + 10).map(|i| i as f64 * 0.25).collect(), &[2, 5], false);
+            let single = gradcheck_tensor(
+                |inputs| (&inputs[0] * &inputs[1]).tanh().mul_elem(&weights).sum(),
+                &[x.at(&[b]), w.clone()],
+                EPS
+            );
+            for (acc, grad) in summed.iter_mut().zip(single.analytic[1].iter().flatten()) {
+                *acc += grad;
+            }
+        }
+        for (summed, grad) in summed.iter().zip(batched.analytic[1].iter().flatten()) {
+            assert!((summed - grad).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn dense_broadcasting() {
+        // The gradient of an operand is summed over the dimensions it is repeated along
+        let x = dense(&[3, 2]);
+        for shape in [vec![1, 2], vec![3, 1], vec![1, 1], vec![2], vec![]] {
+            let name = format!("[3, 2] * {:?}", shape);
+            check_dense(&name, |inputs| weighted(&inputs[0].mul_elem(&inputs[1]).tanh()), &[x.clone(), dense(&shape)]);
+        }
+        check_dense("[3, 1] / [1, 2]", |inputs| weighted(&inputs[0].div_elem(&inputs[1])), &[dense(&[3, 1]), dense(&[1, 2])]);
+        check_dense("[2, 3, 2] max [3, 1]", |inputs| weighted(&inputs[0].max(&inputs[1])), &[dense(&[2, 3, 2]), dense(&[3, 1])]);
+    }
+}
+
@@ -1,21 +1,19 @@
+use crate::lib::float::Float;
 use crate::lib::grad::Scalar;
 use crate::lib::tensor::Tensor2D;
 
 // MSE loss
-pub fn mse(y_pred: &Vec<Tensor2D>, y_real: &Vec<Tensor2D>) -> Scalar {
+pub fn mse<T: Float>(y_pred: &Vec<Tensor2D<T>>, y_real: &Vec<Tensor2D<T>>) -> Scalar<T> {
     // Assert both vectors have the same length
     assert_eq!(y_pred.len(), y_real.len());
 
-    let mut loss: Scalar = Scalar::new(0.0, true);
+    // Sum all the squared errors in a single node
+    let mut errors: Vec<Scalar<T>> = Vec::new();
 
     for (pred, real) in y_pred.iter().zip(y_real.iter()) {
-        let loss_2d = (pred - real).pow(2);
-        for row in 0..loss_2d.rows {
-            for col in 0..loss_2d.cols {
-                loss = &loss + &loss_2d.data[row][col];
-            }
-        }
+        let loss_2d = (pred - real).pow(T::from_f64(2.0));
+        errors.extend(loss_2d.data.into_iter().flatten());
     }
 
-    loss
+    Scalar::sum(&errors)
 }
@@ -1,8 +1,8 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
+use crate::lib::dot::DotGroup;
+use crate::lib::float::Float;
 use crate::lib::grad::Activation;
 use crate::lib::tensor::Tensor2D;
+use crate::lib::shared::{ Lock, Shared };
 
 use super::grad::Data;
 use super::grad::Scalar;
@@ -10,14 +10,14 @@ use super::grad::Scalar;
 // use super::loss;
 
 // Neuron
-pub struct Neuron {
-    pub weights: Tensor2D,
-    pub bias: Tensor2D,
-    activation: Activation,
+pub struct Neuron<T: Float = f32> {
+    pub weights: Tensor2D<T>,
+    pub bias: Tensor2D<T>,
+    activation: Activation<T>,
 }
 
-impl Neuron {
-    pub fn new(size: usize, activation: Activation) -> Self {
+impl<T: Float> Neuron<T> {
+    pub fn new(size: usize, activation: Activation<T>) -> Self {
         Self {
             weights: Tensor2D::xavier(1, size, true),
             bias: Tensor2D::xavier(1, 1, true),
@@ -27,27 +27,22 @@ impl Neuron {
 
     // Input shape is (1, n)
     // Weights shape is (n, 1)
-    pub fn forward(&self, input: &Tensor2D) -> Tensor2D {
+    pub fn forward(&self, input: &Tensor2D<T>) -> Tensor2D<T> {
         let out = &(input * &self.weights.transpose()) + &self.bias;
-        match self.activation {
-            Activation::Exp => out.exp(),
-            Activation::Tanh => out.tanh(),
-            Activation::Sigmoid => out.sigmoid(),
-            Activation::ReLU => out.relu(),
-        }
+        Tensor2D::nonlinear(&out, self.activation.clone())
     }
 
-    pub fn params(&self) -> Vec<Rc<RefCell<Data>>> {
-        let mut params: Vec<Rc<RefCell<Data>>> = Vec::new();
+    pub fn params(&self) -> Vec<Shared<Lock<Data<T>>>> {
+        let mut params: Vec<Shared<Lock<Data<T>>>> = Vec::new();
 
         for row in self.weights.data.iter() {
             for scalar in row.iter() {
-                params.push(Rc::clone(&scalar.data));
+                params.push(Shared::clone(&scalar.data));
             }
         }
         for row in self.bias.data.iter() {
             for scalar in row.iter() {
-                params.push(Rc::clone(&scalar.data));
+                params.push(Shared::clone(&scalar.data));
             }
         }
         params
@@ -55,12 +50,12 @@ impl Neuron {
 }
 
 // Layer
-pub struct Layer {
-    pub neurons: Vec<Neuron>,
+pub struct Layer<T: Float = f32> {
+    pub neurons: Vec<Neuron<T>>,
 }
 
-impl Layer {
-    pub fn new(in_size: usize, out_size: usize, activation: Activation) -> Self {
+impl<T: Float> Layer<T> {
+    pub fn new(in_size: usize, out_size: usize, activation: Activation<T>) -> Self {
         let mut neurons = Vec::new();
         for _ in 0..out_size {
             neurons.push(Neuron::new(in_size, activation.clone()));
@@ -68,7 +63,7 @@ impl Layer {
         Self { neurons }
     }
 
-    pub fn forward(&self, input: &Tensor2D) -> Tensor2D {
+    pub fn forward(&self, input: &Tensor2D<T>) -> Tensor2D<T> {
         let mut output = Tensor2D::zeros(1, self.neurons.len(), false);
         for (i, neuron) in self.neurons.iter().enumerate() {
             output.data[0][i] = neuron.forward(input).data[0][0].clone();
@@ -76,8 +71,8 @@ impl Layer {
         output
     }
 
-    pub fn params(&self) -> Vec<Rc<RefCell<Data>>> {
-        let mut params: Vec<Rc<RefCell<Data>>> = Vec::new();
+    pub fn params(&self) -> Vec<Shared<Lock<Data<T>>>> {
+        let mut params: Vec<Shared<Lock<Data<T>>>> = Vec::new();
         for neuron in self.neurons.iter() {
             params.append(&mut neuron.params());
         }
@@ -86,25 +81,21 @@ impl Layer {
 }
 
 // MLP
-pub struct MLP {
-    pub layers: Vec<Layer>,
-    pub topological: Option<Vec<Rc<RefCell<Data>>>>,
+pub struct MLP<T: Float = f32> {
+    pub layers: Vec<Layer<T>>,
 }
 
-impl MLP {
-    pub fn new(sizes: Vec<usize>, activation: Activation) -> Self {
+impl<T: Float> MLP<T> {
+    pub fn new(sizes: Vec<usize>, activation: Activation<T>) -> Self {
         let mut layers = Vec::new();
         for i in 0..sizes.len() - 1 {
             layers.push(Layer::new(sizes[i], sizes[i + 1], activation.clone()));
         }
-        Self {
-            layers,
-            topological: None,
-        }
+        Self { layers }
     }
 
-    pub fn forward(&self, input: &Tensor2D) -> Tensor2D {
-        let mut output: Tensor2D = input.clone();
+    pub fn forward(&self, input: &Tensor2D<T>) -> Tensor2D<T> {
+        let mut output: Tensor2D<T> = input.clone();
 
         for layer in self.layers.iter() {
             output = layer.forward(&output);
@@ -113,24 +104,75 @@ impl MLP {
         output
     }
 
-    pub fn params(&self) -> Vec<Rc<RefCell<Data>>> {
-        let mut params: Vec<Rc<RefCell<Data>>> = Vec::new();
+    pub fn params(&self) -> Vec<Shared<Lock<Data<T>>>> {
+        let mut params: Vec<Shared<Lock<Data<T>>>> = Vec::new();
         for layer in self.layers.iter() {
             params.append(&mut layer.params());
         }
         params
     }
 
-    pub fn backward(&mut self, loss: &Scalar) {
-        match &self.topological {
-            Some(order) => {
-                for node in order {
-                    Data::backward(Rc::clone(node));
-                }
-            }
-            None => {
-                self.topological = Some(loss.backward());
+    // Resets the gradients of every parameter, including those recorded by backward_create_graph
+    pub fn zero_grad(&self) {
+        for param in self.params() {
+            Data::zero_grad(&param);
+        }
+    }
+
+    // One cluster per layer, holding one cluster per neuron with its parameters, for DotOptions::groups.
+    // The demo binary never exports its graph.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn dot_groups(&self) -> Vec<DotGroup<T>> {
+        let mut groups = Vec::new();
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut neurons = Vec::new();
+            for (j, neuron) in layer.neurons.iter().enumerate() {
+                neurons.push(DotGroup {
+                    label: format!("Neuron {}", j),
+                    nodes: neuron.params(),
+                    groups: Vec::new(),
+                });
             }
+            groups.push(DotGroup {
+                label: format!("Layer {}", i),
+                nodes: Vec::new(),
+                groups: neurons,
+            });
         }
+
+        groups
+    }
+
+    // Each forward pass builds a new graph, so the topological order is computed again every time.
+    // The graph of the loss is freed once the gradients reach the parameters.
+    pub fn backward(&self, loss: &Scalar<T>) {
+        loss.backward();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::loss;
+
+    #[test]
+    fn zero_grad_frees_the_create_graph() {
+        let nn: MLP<f64> = MLP::new(vec![3, 4, 1], Activation::Tanh);
+
+        let output = {
+            let pred = nn.forward(&Tensor2D::row(vec![2.0, 3.0, -1.0]));
+            let output = Shared::downgrade(&pred.data[0][0].data);
+            let mse = loss::mse(&vec![pred], &vec![Tensor2D::scalar(1.0)]);
+            mse.backward_create_graph();
+            output
+        };
+
+        // Only the parameters keep a differentiable gradient, and it still holds the graph
+        assert!(nn.params().iter().all(|param| param.borrow().grad_graph.is_some()));
+        assert!(output.upgrade().is_some());
+
+        nn.zero_grad();
+        assert!(output.upgrade().is_none());
     }
 }
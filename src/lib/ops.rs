@@ -1,11 +1,12 @@
+use crate::lib::float::Float;
 use crate::lib::grad::Data;
 use crate::lib::grad::Dependency;
+use crate::lib::grad::Nonlinear;
 use crate::lib::grad::Scalar;
+use crate::lib::shared::{ Lock, Shared };
 use crate::lib::tensor::Tensor2D;
 
-use std::cell::RefCell;
-use std::ops::{ Add, Div, Mul, Sub };
-use std::rc::Rc;
+use std::ops::{ Add, BitXor, Div, Mul, Sub };
 
 #[derive(Debug, Clone)]
 pub enum Operation {
@@ -13,204 +14,363 @@ pub enum Operation {
     Mul,
     Sub,
     Div,
+    Pow,
+    Max,
+    Min,
+    // Comparisons, giving 1.0 where they hold and 0.0 elsewhere
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
 }
 
-fn op(lhs: &Scalar, rhs: &Scalar, op: Operation) -> Scalar {
-    // let requires_grad: bool = lhs.data.borrow().requires_grad || rhs.data.borrow().requires_grad;
-    let requires_grad: bool = true;
-
-    Scalar {
-        data: Rc::new(
-            RefCell::new(Data {
-                val: match op {
-                    Operation::Add => lhs.val() + rhs.val(),
-                    Operation::Sub => lhs.val() - rhs.val(),
-                    Operation::Mul => lhs.val() * rhs.val(),
-                    Operation::Div => lhs.val() / rhs.val(),
-                },
-                grad: 0.0,
-                dep: match requires_grad {
-                    true =>
-                        Some(Dependency::Double {
-                            lhs: Rc::clone(&lhs.data),
-                            rhs: Rc::clone(&rhs.data),
-                            op,
-                        }),
-                    false => None,
-                },
-                requires_grad,
-            })
-        ),
+// Operations over any number of inputs, recorded as a single node
+#[derive(Debug, Clone)]
+pub enum Reduction {
+    Sum,
+    Mean,
+    Dot,
+}
+
+impl Operation {
+    // Value of the operation, used both when building a node and when replaying a graph
+    pub fn apply<T: Float>(&self, x: T, y: T) -> T {
+        match self {
+            Operation::Add => x + y,
+            Operation::Sub => x - y,
+            Operation::Mul => x * y,
+            Operation::Div => x / y,
+            Operation::Pow => x.powf(y),
+            Operation::Max => x.max(y),
+            Operation::Min => x.min(y),
+            Operation::Gt => indicator(x > y),
+            Operation::Ge => indicator(x >= y),
+            Operation::Lt => indicator(x < y),
+            Operation::Le => indicator(x <= y),
+            Operation::Eq => indicator(x == y),
+            Operation::Ne => indicator(x != y),
+        }
+    }
+
+    // Partial derivatives of the operation with respect to x and y, where out is the value it took there.
+    // Shared by the backward pass of Scalars and of Tensors.
+    pub fn partials<T: Float>(&self, x: T, y: T, out: T) -> (T, T) {
+        match self {
+            // Addition means: f(x, y) = x + y, f'(x) = 1, f'(y) = 1
+            Operation::Add => (T::one(), T::one()),
+            // Subtraction means: f(x, y) = x - y, f'(x) = 1, f'(y) = -1
+            Operation::Sub => (T::one(), -T::one()),
+            // Multiplication means: f(x, y) = x * y, f'(x) = y, f'(y) = x
+            Operation::Mul => (y, x),
+            // Division means: f(x, y) = x / y, f'(x) = 1 / y, f'(y) = -x / y^2
+            Operation::Div => (T::one() / y, -x / y.powi(2)),
+            // Power means: f(x, y) = x^y, f'(x) = y * x^(y - 1), f'(y) = x^y * ln(x)
+            // For y = 0 the function is constant in x, even at x = 0 where x^(y - 1) is infinite.
+            // The logarithm is only defined for x > 0, elsewhere the derivative with respect to y is taken to be 0.
+            Operation::Pow => (
+                if y == T::zero() { T::zero() } else { y * x.powf(y - T::one()) },
+                if x > T::zero() { out * x.ln() } else { T::zero() },
+            ),
+            // Max means: f(x, y) = x if x > y, y otherwise, f'(x) = 1 if x > y, f'(y) = 1 if y > x
+            // Min is the same with the roles swapped.
+            // On ties the subgradient is split evenly, so the same operand on both sides gets the whole gradient.
+            Operation::Max | Operation::Min => Operation::select_weights(self, x, y),
+            // Comparisons are piecewise constant, so their derivative is taken to be 0 everywhere
+            Operation::Gt | Operation::Ge | Operation::Lt | Operation::Le | Operation::Eq | Operation::Ne => {
+                (T::zero(), T::zero())
+            }
+        }
+    }
+
+    // Share of the gradient going to each operand of a max or min.
+    // Ties split it evenly, which is a valid subgradient.
+    pub fn select_weights<T: Float>(op: &Operation, x: T, y: T) -> (T, T) {
+        let lhs_selected = match op {
+            Operation::Max => x > y,
+            Operation::Min => x < y,
+            _ => panic!("{} does not select between its operands", op),
+        };
+
+        if x == y {
+            (T::from_f64(0.5), T::from_f64(0.5))
+        } else if lhs_selected {
+            (T::one(), T::zero())
+        } else {
+            (T::zero(), T::one())
+        }
     }
 }
 
-impl Add for &Scalar {
-    type Output = Scalar;
+fn indicator<T: Float>(condition: bool) -> T {
+    if condition { T::one() } else { T::zero() }
+}
 
-    fn add(self, rhs: Self) -> Self::Output {
-        op(self, rhs, Operation::Add)
+fn op<T: Float>(lhs: &Scalar<T>, rhs: &Scalar<T>, op: Operation) -> Scalar<T> {
+    let val = op.apply(lhs.val(), rhs.val());
+    Scalar::from_op(val, Dependency::Double { lhs: Shared::clone(&lhs.data), rhs: Shared::clone(&rhs.data), op })
+}
+
+// Builds the node of a reduction, see Dependency::Reduce for the layout of the inputs
+fn reduce<T: Float>(inputs: Vec<&Scalar<T>>, reduction: Reduction) -> Scalar<T> {
+    let vals: Vec<T> = inputs.iter().map(|input| input.val()).collect();
+    let val = reduction.apply(&vals);
+
+    let operands: Vec<Shared<Lock<Data<T>>>> = inputs
+        .iter()
+        .map(|input| Shared::clone(&input.data))
+        .collect();
+    Scalar::from_op(val, Dependency::Reduce { inputs: operands, reduction })
+}
+
+impl Reduction {
+    // Value of the reduction, see Dependency::Reduce for the layout of the inputs
+    pub fn apply<T: Float>(&self, inputs: &[T]) -> T {
+        match self {
+            Reduction::Sum => inputs.iter().copied().sum(),
+            Reduction::Mean => inputs.iter().copied().sum::<T>() / T::from_usize(inputs.len()),
+            Reduction::Dot => inputs
+                .chunks(2)
+                .map(|pair| pair[0] * pair[1])
+                .sum(),
+        }
     }
 }
 
-impl Add<f32> for &Scalar {
-    type Output = Scalar;
+impl<T: Float> Scalar<T> {
+    pub fn sum(inputs: &[Scalar<T>]) -> Scalar<T> {
+        reduce(inputs.iter().collect(), Reduction::Sum)
+    }
 
-    fn add(self, rhs: f32) -> Self::Output {
-        op(self, &Scalar::new(rhs, false), Operation::Add)
+    pub fn mean(inputs: &[Scalar<T>]) -> Scalar<T> {
+        assert!(!inputs.is_empty(), "Cannot take the mean of no values");
+
+        reduce(inputs.iter().collect(), Reduction::Mean)
+    }
+
+    pub fn dot(lhs: &[Scalar<T>], rhs: &[Scalar<T>]) -> Scalar<T> {
+        assert_eq!(lhs.len(), rhs.len());
+
+        let inputs: Vec<&Scalar<T>> = lhs
+            .iter()
+            .zip(rhs.iter())
+            .flat_map(|(x, y)| [x, y])
+            .collect();
+        reduce(inputs, Reduction::Dot)
     }
 }
 
-impl Add<&Scalar> for f32 {
-    type Output = Scalar;
+impl<T: Float> Add for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn add(self, rhs: &Scalar) -> Self::Output {
-        op(&Scalar::new(self, false), rhs, Operation::Add)
+    fn add(self, rhs: Self) -> Self::Output {
+        op(self, rhs, Operation::Add)
     }
 }
 
-impl Sub for &Scalar {
-    type Output = Scalar;
+impl<T: Float> Add<T> for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        op(self, rhs, Operation::Sub)
+    fn add(self, rhs: T) -> Self::Output {
+        op(self, &Scalar::new(rhs, false), Operation::Add)
     }
 }
 
-impl Sub<f32> for &Scalar {
-    type Output = Scalar;
+impl<T: Float> Sub for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn sub(self, rhs: f32) -> Self::Output {
-        op(self, &Scalar::new(rhs, false), Operation::Sub)
+    fn sub(self, rhs: Self) -> Self::Output {
+        op(self, rhs, Operation::Sub)
     }
 }
 
-impl Sub<&Scalar> for f32 {
-    type Output = Scalar;
+impl<T: Float> Sub<T> for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn sub(self, rhs: &Scalar) -> Self::Output {
-        op(&Scalar::new(self, false), rhs, Operation::Sub)
+    fn sub(self, rhs: T) -> Self::Output {
+        op(self, &Scalar::new(rhs, false), Operation::Sub)
     }
 }
 
-impl Mul for &Scalar {
-    type Output = Scalar;
+impl<T: Float> Mul for &Scalar<T> {
+    type Output = Scalar<T>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         op(self, rhs, Operation::Mul)
     }
 }
 
-impl Mul<f32> for &Scalar {
-    type Output = Scalar;
+impl<T: Float> Mul<T> for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn mul(self, rhs: f32) -> Self::Output {
+    fn mul(self, rhs: T) -> Self::Output {
         op(self, &Scalar::new(rhs, false), Operation::Mul)
     }
 }
 
-impl Mul<&Scalar> for f32 {
-    type Output = Scalar;
+impl<T: Float> Div for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn mul(self, rhs: &Scalar) -> Self::Output {
-        op(&Scalar::new(self, false), rhs, Operation::Mul)
+    fn div(self, rhs: Self) -> Self::Output {
+        op(self, rhs, Operation::Div)
     }
 }
 
-impl Div for &Scalar {
-    type Output = Scalar;
+impl<T: Float> Div<T> for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn div(self, rhs: Self) -> Self::Output {
-        op(self, rhs, Operation::Div)
+    fn div(self, rhs: T) -> Self::Output {
+        op(self, &Scalar::new(rhs, false), Operation::Div)
     }
 }
 
-impl Div<f32> for &Scalar {
-    type Output = Scalar;
+// Power, differentiable in both the base and the exponent
+impl<T: Float> BitXor for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn div(self, rhs: f32) -> Self::Output {
-        op(self, &Scalar::new(rhs, false), Operation::Div)
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        op(self, rhs, Operation::Pow)
     }
 }
 
-impl Div<&Scalar> for f32 {
-    type Output = Scalar;
+impl<T: Float> BitXor<T> for &Scalar<T> {
+    type Output = Scalar<T>;
 
-    fn div(self, rhs: &Scalar) -> Self::Output {
-        op(&Scalar::new(self, false), rhs, Operation::Div)
+    fn bitxor(self, rhs: T) -> Self::Output {
+        self.pow(rhs)
     }
 }
 
-impl Add for &Tensor2D {
-    type Output = Tensor2D;
+impl<T: Float> Scalar<T> {
+    pub fn max(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Max)
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        assert_eq!(self.rows, rhs.rows);
-        assert_eq!(self.cols, rhs.cols);
+    pub fn min(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Min)
+    }
 
-        let mut ans = Tensor2D::zeros(self.rows, self.cols, false);
+    // The comparisons give a 0/1 mask. They are recorded like any other operation, so that a replay
+    // of the graph evaluates them again, but no gradient flows through them.
+    pub fn gt(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Gt)
+    }
 
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                ans.data[row][col] = op(&self.data[row][col], &rhs.data[row][col], Operation::Add);
-            }
-        }
+    pub fn ge(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Ge)
+    }
 
-        ans
+    pub fn lt(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Lt)
     }
-}
 
-impl Add<f32> for &Tensor2D {
-    type Output = Tensor2D;
+    pub fn le(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Le)
+    }
 
-    fn add(self, rhs: f32) -> Self::Output {
-        let mut ans = Tensor2D::zeros(self.rows, self.cols, false);
+    pub fn eq(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Eq)
+    }
 
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                ans.data[row][col] = op(
-                    &self.data[row][col],
-                    &Scalar::new(rhs, false),
-                    Operation::Add
-                );
-            }
-        }
+    pub fn ne(&self, rhs: &Scalar<T>) -> Scalar<T> {
+        op(self, rhs, Operation::Ne)
+    }
 
-        ans
+    // where(mask, a, b): a if the mask is non zero, b otherwise.
+    // The gradient only flows back to the selected operand, and the mask is read again when replaying the graph.
+    pub fn select(mask: &Scalar<T>, a: &Scalar<T>, b: &Scalar<T>) -> Scalar<T> {
+        let val = if mask.val() != T::zero() { a.val() } else { b.val() };
+        Scalar::from_op(val, Dependency::Select {
+            mask: Shared::clone(&mask.data),
+            a: Shared::clone(&a.data),
+            b: Shared::clone(&b.data),
+        })
     }
 }
 
-impl Add<&Tensor2D> for f32 {
-    type Output = Tensor2D;
+// Size of a dimension once two operands are broadcast together: sizes must match, unless one of them is 1
+pub fn broadcast_dim(lhs: usize, rhs: usize) -> usize {
+    match (lhs, rhs) {
+        _ if lhs == rhs => lhs,
+        (1, _) => rhs,
+        (_, 1) => lhs,
+        _ => panic!("Cannot broadcast a dimension of size {} with one of size {}", lhs, rhs),
+    }
+}
 
-    fn add(self, rhs: &Tensor2D) -> Self::Output {
-        let mut ans = Tensor2D::zeros(rhs.rows, rhs.cols, false);
+impl<T: Float> Tensor2D<T> {
+    // The element used at (row, col) once the tensor is broadcast: a row vector repeats its only row,
+    // a column vector its only column and a 1x1 tensor its only element
+    fn broadcast_at(&self, row: usize, col: usize) -> &Scalar<T> {
+        let row = if self.rows == 1 { 0 } else { row };
+        let col = if self.cols == 1 { 0 } else { col };
+        &self.data[row][col]
+    }
 
-        for row in 0..rhs.rows {
-            for col in 0..rhs.cols {
-                ans.data[row][col] = op(
-                    &Scalar::new(self, false),
-                    &rhs.data[row][col],
-                    Operation::Add
-                );
+    // Applies f to every pair of elements, broadcasting the operands to a common shape.
+    // A Scalar reused across the broadcast dimensions gets the sum of the gradients of all its uses.
+    fn zip_with(&self, rhs: &Tensor2D<T>, f: impl Fn(&Scalar<T>, &Scalar<T>) -> Scalar<T>) -> Tensor2D<T> {
+        let rows = broadcast_dim(self.rows, rhs.rows);
+        let cols = broadcast_dim(self.cols, rhs.cols);
+
+        let mut ans = Tensor2D::zeros(rows, cols, false);
+
+        for row in 0..rows {
+            for col in 0..cols {
+                ans.data[row][col] = f(self.broadcast_at(row, col), rhs.broadcast_at(row, col));
             }
         }
 
         ans
     }
-}
 
-impl Sub for &Tensor2D {
-    type Output = Tensor2D;
+    pub fn max(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, |x, y| op(x, y, Operation::Max))
+    }
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        assert_eq!(self.rows, rhs.rows);
-        assert_eq!(self.cols, rhs.cols);
+    pub fn min(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, |x, y| op(x, y, Operation::Min))
+    }
 
-        let mut ans = Tensor2D::zeros(self.rows, self.cols, false);
+    pub fn gt(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, Scalar::gt)
+    }
 
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                ans.data[row][col] = op(&self.data[row][col], &rhs.data[row][col], Operation::Sub);
+    pub fn ge(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, Scalar::ge)
+    }
+
+    pub fn lt(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, Scalar::lt)
+    }
+
+    pub fn le(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, Scalar::le)
+    }
+
+    pub fn eq(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, Scalar::eq)
+    }
+
+    pub fn ne(&self, rhs: &Tensor2D<T>) -> Tensor2D<T> {
+        self.zip_with(rhs, Scalar::ne)
+    }
+
+    // Element-wise where(mask, a, b), see Scalar::select, the three tensors are broadcast together
+    pub fn select(mask: &Tensor2D<T>, a: &Tensor2D<T>, b: &Tensor2D<T>) -> Tensor2D<T> {
+        let rows = broadcast_dim(mask.rows, broadcast_dim(a.rows, b.rows));
+        let cols = broadcast_dim(mask.cols, broadcast_dim(a.cols, b.cols));
+
+        let mut ans = Tensor2D::zeros(rows, cols, false);
+
+        for row in 0..rows {
+            for col in 0..cols {
+                ans.data[row][col] = Scalar::select(
+                    mask.broadcast_at(row, col),
+                    a.broadcast_at(row, col),
+                    b.broadcast_at(row, col)
+                );
             }
         }
 
@@ -218,10 +378,18 @@ impl Sub for &Tensor2D {
     }
 }
 
-impl Sub<f32> for &Tensor2D {
-    type Output = Tensor2D;
+impl<T: Float> Add for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, |x, y| op(x, y, Operation::Add))
+    }
+}
+
+impl<T: Float> Add<T> for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
 
-    fn sub(self, rhs: f32) -> Self::Output {
+    fn add(self, rhs: T) -> Self::Output {
         let mut ans = Tensor2D::zeros(self.rows, self.cols, false);
 
         for row in 0..self.rows {
@@ -229,7 +397,7 @@ impl Sub<f32> for &Tensor2D {
                 ans.data[row][col] = op(
                     &self.data[row][col],
                     &Scalar::new(rhs, false),
-                    Operation::Sub
+                    Operation::Add
                 );
             }
         }
@@ -238,17 +406,25 @@ impl Sub<f32> for &Tensor2D {
     }
 }
 
-impl Sub<&Tensor2D> for f32 {
-    type Output = Tensor2D;
+impl<T: Float> Sub for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
 
-    fn sub(self, rhs: &Tensor2D) -> Self::Output {
-        let mut ans = Tensor2D::zeros(rhs.rows, rhs.cols, false);
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, |x, y| op(x, y, Operation::Sub))
+    }
+}
+
+impl<T: Float> Sub<T> for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
 
-        for row in 0..rhs.rows {
-            for col in 0..rhs.cols {
+    fn sub(self, rhs: T) -> Self::Output {
+        let mut ans = Tensor2D::zeros(self.rows, self.cols, false);
+
+        for row in 0..self.rows {
+            for col in 0..self.cols {
                 ans.data[row][col] = op(
-                    &Scalar::new(self, false),
-                    &rhs.data[row][col],
+                    &self.data[row][col],
+                    &Scalar::new(rhs, false),
                     Operation::Sub
                 );
             }
@@ -258,8 +434,8 @@ impl Sub<&Tensor2D> for f32 {
     }
 }
 
-impl Mul for &Tensor2D {
-    type Output = Tensor2D;
+impl<T: Float> Mul for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         assert_eq!(
@@ -276,36 +452,19 @@ impl Mul for &Tensor2D {
 
         for i in 0..self.rows {
             for j in 0..rhs.cols {
-                let mut sum: Option<Scalar> = None;
-
-                for k in 0..self.cols {
-                    match &sum {
-                        None => {
-                            sum = Some(op(&self.data[i][k], &rhs.data[k][j], Operation::Mul));
-                        }
-                        Some(_) => {
-                            sum = Some(
-                                op(
-                                    &sum.unwrap(),
-                                    &op(&self.data[i][k], &rhs.data[k][j], Operation::Mul),
-                                    Operation::Add
-                                )
-                            );
-                        }
-                    }
-                }
-
-                ans.data[i][j] = sum.unwrap();
+                // Each element is the dot product of a row and a column, recorded as a single node
+                let col: Vec<Scalar<T>> = (0..rhs.rows).map(|k| rhs.data[k][j].clone()).collect();
+                ans.data[i][j] = Scalar::dot(&self.data[i], &col);
             }
         }
         ans
     }
 }
 
-impl Mul<f32> for &Tensor2D {
-    type Output = Tensor2D;
+impl<T: Float> Mul<T> for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
 
-    fn mul(self, rhs: f32) -> Self::Output {
+    fn mul(self, rhs: T) -> Self::Output {
         let mut ans = Tensor2D::zeros(self.rows, self.cols, false);
 
         for row in 0..self.rows {
@@ -322,22 +481,155 @@ impl Mul<f32> for &Tensor2D {
     }
 }
 
-impl Mul<&Tensor2D> for f32 {
-    type Output = Tensor2D;
+// Element-wise power
+impl<T: Float> BitXor for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
 
-    fn mul(self, rhs: &Tensor2D) -> Self::Output {
-        let mut ans = Tensor2D::zeros(rhs.rows, rhs.cols, false);
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, |x, y| op(x, y, Operation::Pow))
+    }
+}
 
-        for row in 0..rhs.rows {
-            for col in 0..rhs.cols {
-                ans.data[row][col] = op(
-                    &Scalar::new(self, false),
-                    &rhs.data[row][col],
-                    Operation::Mul
-                );
+impl<T: Float> BitXor<T> for &Tensor2D<T> {
+    type Output = Tensor2D<T>;
+
+    fn bitxor(self, rhs: T) -> Self::Output {
+        self.pow(rhs)
+    }
+}
+
+// Operators with a plain float on the left hand side.
+// They cannot be implemented for a generic T, so they are generated for each primitive float type.
+macro_rules! impl_float_lhs {
+    ($t:ty) => {
+        impl_float_lhs!(@scalar $t, Add, add, Operation::Add);
+        impl_float_lhs!(@scalar $t, Sub, sub, Operation::Sub);
+        impl_float_lhs!(@scalar $t, Mul, mul, Operation::Mul);
+        impl_float_lhs!(@scalar $t, Div, div, Operation::Div);
+        impl_float_lhs!(@scalar $t, BitXor, bitxor, Operation::Pow);
+        impl_float_lhs!(@tensor $t, Add, add, Operation::Add);
+        impl_float_lhs!(@tensor $t, Sub, sub, Operation::Sub);
+        impl_float_lhs!(@tensor $t, Mul, mul, Operation::Mul);
+        impl_float_lhs!(@tensor $t, BitXor, bitxor, Operation::Pow);
+    };
+    (@scalar $t:ty, $trait:ident, $method:ident, $op:expr) => {
+        impl $trait<&Scalar<$t>> for $t {
+            type Output = Scalar<$t>;
+
+            fn $method(self, rhs: &Scalar<$t>) -> Self::Output {
+                op(&Scalar::new(self, false), rhs, $op)
             }
         }
+    };
+    (@tensor $t:ty, $trait:ident, $method:ident, $op:expr) => {
+        impl $trait<&Tensor2D<$t>> for $t {
+            type Output = Tensor2D<$t>;
+
+            fn $method(self, rhs: &Tensor2D<$t>) -> Self::Output {
+                let mut ans = Tensor2D::zeros(rhs.rows, rhs.cols, false);
+
+                for row in 0..rhs.rows {
+                    for col in 0..rhs.cols {
+                        ans.data[row][col] = op(
+                            &Scalar::new(self, false),
+                            &rhs.data[row][col],
+                            $op
+                        );
+                    }
+                }
 
-        ans
+                ans
+            }
+        }
+    };
+}
+
+impl_float_lhs!(f32);
+impl_float_lhs!(f64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_exponent_has_zero_gradient() {
+        // x^0 is constant, even at x = 0 where p * x^(p - 1) would be 0 * inf
+        let x = Scalar::new(0.0, true);
+        x.pow(0.0).backward();
+        assert_eq!(x.grad(), 0.0);
+
+        let (x, e) = (Scalar::new(0.0, true), Scalar::new(0.0, true));
+        (&x ^ &e).backward();
+        assert_eq!((x.grad(), e.grad()), (0.0, 0.0));
+
+        // The same holds for the differentiable gradients
+        let (x, e) = (Scalar::new(0.0, true), Scalar::new(0.0, true));
+        (&x.pow(0.0) + &(&x ^ &e)).backward_create_graph();
+        assert_eq!(x.grad_graph().map(|grad| grad.val()), Some(0.0));
+    }
+
+    #[test]
+    fn select_records_its_own_node() {
+        let (a, b) = (Scalar::new(1.0, true), Scalar::new(2.0, true));
+        let s = Scalar::select(&a.gt(&b), &a, &b);
+        assert_eq!(s.val(), 2.0);
+
+        // The hook belongs to the result of the select, not to b
+        s.register_hook(|grad| grad * 100.0);
+        assert!(b.data.borrow().hooks.is_empty());
+
+        (&(&b * 2.0) + &s).backward();
+        assert_eq!(b.grad(), 2.0 + 100.0);
+        assert_eq!(a.grad(), 0.0);
+    }
+
+    #[test]
+    fn select_sends_the_gradient_to_the_selected_operand() {
+        let (a, b) = (Tensor2D::zeros(1, 2, true), Tensor2D::zeros(1, 2, true));
+        for (x, val) in a.data[0].iter().chain(b.data[0].iter()).zip([1.0, 5.0, 3.0, 4.0]) {
+            x.data.borrow_mut().val = val;
+        }
+
+        // The element-wise maximum
+        let s = Tensor2D::select(&a.gt(&b), &a, &b);
+        assert_eq!(s.data[0].iter().map(|x| x.val()).collect::<Vec<f32>>(), vec![3.0, 5.0]);
+        s.sum().backward();
+        assert_eq!((a.data[0][0].grad(), a.data[0][1].grad()), (0.0, 1.0));
+        assert_eq!((b.data[0][0].grad(), b.data[0][1].grad()), (1.0, 0.0));
+    }
+
+    #[test]
+    fn broadcast_shapes() {
+        let x = Tensor2D::from(vec![vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]]);
+        let vals = |t: &Tensor2D<f32>| -> Vec<Vec<f32>> {
+            t.data.iter().map(|row| row.iter().map(|x| x.val()).collect()).collect()
+        };
+
+        assert_eq!(vals(&(&x + &Tensor2D::row(vec![10.0, 20.0]))), vec![vec![11.0, 22.0], vec![13.0, 24.0], vec![15.0, 26.0]]);
+        assert_eq!(vals(&(&x - &Tensor2D::col(vec![1.0, 2.0, 3.0]))), vec![vec![0.0, 1.0], vec![1.0, 2.0], vec![2.0, 3.0]]);
+        assert_eq!(vals(&(&Tensor2D::scalar(1.0) + &x)), vec![vec![2.0, 3.0], vec![4.0, 5.0], vec![6.0, 7.0]]);
+
+        // A column and a row broadcast to their outer shape
+        let outer = &Tensor2D::col(vec![1.0, 2.0, 3.0]) + &Tensor2D::row(vec![10.0, 20.0]);
+        assert_eq!((outer.rows, outer.cols), (3, 2));
+        assert_eq!(vals(&outer), vec![vec![11.0, 21.0], vec![12.0, 22.0], vec![13.0, 23.0]]);
+    }
+
+    #[test]
+    fn broadcasting_sums_the_gradient_over_the_repeated_dimensions() {
+        let x = Tensor2D::from(vec![vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]]);
+        let (row, col, scalar) = (Tensor2D::zeros(1, 2, true), Tensor2D::zeros(3, 1, true), Tensor2D::zeros(1, 1, true));
+
+        (&(&(&x + &row) - &col) + &scalar).sum().backward();
+        assert!(row.data[0].iter().all(|x| x.grad() == 3.0));
+        assert!(col.data.iter().all(|row| row[0].grad() == -2.0));
+        assert_eq!(scalar.data[0][0].grad(), 6.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot broadcast a dimension of size 3 with one of size 2")]
+    fn incompatible_shapes_do_not_broadcast() {
+        let _ = &Tensor2D::<f32>::zeros(3, 2, false) + &Tensor2D::zeros(2, 2, false);
     }
 }
+
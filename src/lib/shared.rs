@@ -0,0 +1,195 @@
+// Shared ownership and interior mutability of the nodes of the graph.
+// By default the graph lives on a single thread and uses Rc and RefCell.
+// With the `sync` feature it uses Arc and RwLock instead, so that scalars, tensors and models are Send + Sync.
+// The rest of the crate only refers to Shared and Lock, so it reads the same in both cases.
+
+#[cfg(not(feature = "sync"))]
+pub use std::rc::Rc as Shared;
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Shared;
+
+#[cfg(not(feature = "sync"))]
+pub use std::cell::RefCell as Lock;
+
+#[cfg(feature = "sync")]
+use std::sync::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+
+// RwLock exposing the same methods as RefCell.
+// Unlike RefCell, two shared borrows of the same node are not always fine: a thread taking a second read lock
+// on a node it already reads waits behind any writer queued in between, and deadlocks. So never hold a guard
+// on a node while borrowing it again, copy what is needed out of the first guard instead.
+#[cfg(feature = "sync")]
+#[derive(Debug, Default)]
+pub struct Lock<T>(RwLock<T>);
+
+#[cfg(feature = "sync")]
+impl<T> Lock<T> {
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(value))
+    }
+
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.0.read().expect("A thread panicked while holding a node")
+    }
+
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().expect("A thread panicked while holding a node")
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().expect("A thread panicked while holding a node")
+    }
+}
+
+// Bound of the closures and operations stored in the graph, Send + Sync with the `sync` feature
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+#[cfg(feature = "sync")]
+pub trait MaybeSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> MaybeSync for T {}
+
+// Closure run by a gradient hook, see Hook
+#[cfg(not(feature = "sync"))]
+pub type HookFn<T> = dyn Fn(T) -> T;
+#[cfg(feature = "sync")]
+pub type HookFn<T> = dyn Fn(T) -> T + Send + Sync;
+
+#[cfg(all(test, feature = "sync"))]
+mod tests {
+    use super::Shared;
+    use crate::lib::dense::Tensor;
+    use crate::lib::grad::{ Activation, Data, Scalar };
+    use crate::lib::loss;
+    use crate::lib::nn::MLP;
+    use crate::lib::tensor::Tensor2D;
+    use std::thread;
+
+    const THREADS: usize = 4;
+    const STEPS: usize = 50;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn send_and_sync() {
+        assert_send_sync::<Scalar>();
+        assert_send_sync::<Tensor2D>();
+        assert_send_sync::<Tensor>();
+        assert_send_sync::<MLP>();
+    }
+
+    // Data-parallel training steps on a shared model: every thread accumulates into the same parameters
+    #[test]
+    fn concurrent_backward() {
+        let nn: Shared<MLP<f64>> = Shared::new(MLP::new(vec![3, 4, 4, 1], Activation::Tanh));
+        let x = Tensor2D::row(vec![2.0, 3.0, -1.0]);
+        let y = vec![Tensor2D::scalar(1.0)];
+
+        // Hooks that read their own parameter while the other threads write to it
+        for param in nn.params() {
+            let node = Shared::downgrade(&param);
+            Data::register_hook(&param, move |grad| {
+                if let Some(node) = node.upgrade() {
+                    assert!(node.borrow().val.is_finite());
+                }
+                grad
+            });
+        }
+
+        let step = |nn: &MLP<f64>| {
+            let mse = loss::mse(&vec![nn.forward(&x)], &y);
+            // The same node on both sides of an operation
+            (&mse * &mse).backward();
+        };
+
+        step(&nn);
+        let expected: Vec<f64> = nn.params().iter().map(|param| param.borrow().grad).collect();
+        nn.zero_grad();
+
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                let nn = Shared::clone(&nn);
+                scope.spawn(move || {
+                    for _ in 0..STEPS {
+                        step(&nn);
+                    }
+                });
+            }
+        });
+
+        let n = (THREADS * STEPS) as f64;
+        for (param, expected) in nn.params().iter().zip(expected) {
+            let grad = param.borrow().grad;
+            assert!((grad - n * expected).abs() <= 1e-9 * (n * expected).abs().max(1.0), "{} != {}", grad, n * expected);
+        }
+    }
+
+    #[test]
+    fn concurrent_tensor_backward() {
+        let w = Tensor::<f64>::uniform(&[3, 3], true);
+
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..STEPS {
+                        (&w * &w).mul_elem(&w).sum().backward();
+                    }
+                });
+            }
+        });
+
+        let expected = {
+            let w = Tensor::new(w.vals(), &[3, 3], true);
+            (&w * &w).mul_elem(&w).sum().backward();
+            w.grads()
+        };
+        let n = (THREADS * STEPS) as f64;
+        for (grad, expected) in w.grads().iter().zip(expected) {
+            assert!((grad - n * expected).abs() <= 1e-9 * (n * expected).abs().max(1.0));
+        }
+    }
+
+    // The hooks of a leaf only see the gradient of their own pass, not the sum of what every thread accumulated so far
+    #[test]
+    fn concurrent_hooks() {
+        let nn: Shared<MLP<f64>> = Shared::new(MLP::new(vec![3, 4, 1], Activation::Tanh));
+        let x = Tensor2D::row(vec![2.0, 3.0, -1.0]);
+        let y = vec![Tensor2D::scalar(1.0)];
+        for param in nn.params() {
+            Data::register_hook(&param, |grad| grad.clamp(-0.01, 0.01));
+        }
+        let w = Tensor::<f64>::uniform(&[3, 3], true);
+        w.register_hook(|grad| grad.clamp(-1.0, 1.0));
+
+        let step = |nn: &MLP<f64>| {
+            loss::mse(&vec![nn.forward(&x)], &y).backward();
+            (&w * &w).mul_elem(&w).sum().backward();
+        };
+
+        step(&nn);
+        let expected: Vec<f64> = nn.params().iter().map(|param| param.borrow().grad).collect();
+        let expected_w = w.grads();
+        nn.zero_grad();
+        w.zero_grad();
+
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                let nn = Shared::clone(&nn);
+                scope.spawn(move || {
+                    for _ in 0..STEPS {
+                        step(&nn);
+                    }
+                });
+            }
+        });
+
+        let n = (THREADS * STEPS) as f64;
+        let grads = nn.params().iter().map(|param| param.borrow().grad).chain(w.grads()).collect::<Vec<f64>>();
+        for (grad, expected) in grads.into_iter().zip(expected.into_iter().chain(expected_w)) {
+            assert!((grad - n * expected).abs() <= 1e-9 * (n * expected).abs().max(1.0), "{} != {}", grad, n * expected);
+        }
+    }
+}
@@ -1,30 +1,32 @@
+use crate::lib::float::Float;
+use crate::lib::grad;
 use crate::lib::grad::Activation;
+use crate::lib::grad::Hook;
 use crate::lib::grad::Nonlinear;
 use crate::lib::grad::Scalar;
+use crate::lib::shared::{ HookFn, Lock, MaybeSync, Shared };
 
 use rand::distributions::Uniform;
 use rand::Rng;
-use std::cell::RefCell;
-use std::rc::Rc;
 use std::vec::Vec;
 
 use super::grad::Data;
 
 #[derive(Debug, Clone)]
-pub struct Tensor2D {
+pub struct Tensor2D<T: Float = f32> {
     pub rows: usize,
     pub cols: usize,
-    pub data: Vec<Vec<Scalar>>,
+    pub data: Vec<Vec<Scalar<T>>>,
 }
 
-impl Tensor2D {
+impl<T: Float> Tensor2D<T> {
     pub fn zeros(rows: usize, cols: usize, requires_grad: bool) -> Self {
         let data = {
             let mut data = Vec::new();
             for _ in 0..rows {
                 let mut row = Vec::new();
                 for _ in 0..cols {
-                    row.push(Scalar::new(0.0, requires_grad));
+                    row.push(Scalar::new(T::zero(), requires_grad));
                 }
                 data.push(row);
             }
@@ -41,7 +43,7 @@ impl Tensor2D {
 
         for row in 0..zeros.rows {
             for col in 0..zeros.cols {
-                zeros.data[row][col].data.borrow_mut().val = rng.sample(side);
+                zeros.data[row][col].data.borrow_mut().val = T::from_f64(rng.sample(side));
             }
         }
 
@@ -60,7 +62,7 @@ impl Tensor2D {
         for row in 0..zeros.rows {
             for col in 0..zeros.cols {
                 zeros.data[row][col].data.borrow_mut().val =
-                    rng.sample(side) / (rows as f32).sqrt();
+                    T::from_f64(rng.sample(side) / (rows as f64).sqrt());
             }
         }
 
@@ -71,7 +73,7 @@ impl Tensor2D {
         }
     }
 
-    pub fn from(vec: Vec<Vec<f32>>) -> Self {
+    pub fn from(vec: Vec<Vec<T>>) -> Self {
         // Assert that the vector is not empty.
         assert!(!vec.is_empty());
 
@@ -91,7 +93,7 @@ impl Tensor2D {
     }
 
     // From a scalar creates a 1x1 tensor.
-    pub fn scalar(scalar: f32) -> Self {
+    pub fn scalar(scalar: T) -> Self {
         Self {
             rows: 1,
             cols: 1,
@@ -100,15 +102,27 @@ impl Tensor2D {
     }
 
     // From a 1D array reference creates a 1xN 2dtensor
-    pub fn row(vec: Vec<f32>) -> Self {
+    pub fn row(vec: Vec<T>) -> Self {
         Self::from(vec![vec.clone()])
     }
 
-    pub fn col(vec: Vec<f32>) -> Self {
+    pub fn col(vec: Vec<T>) -> Self {
         Self::from(vec![vec.clone()]).transpose()
     }
 
-    pub fn transpose(&self) -> Tensor2D {
+    // Sum of all the elements
+    pub fn sum(&self) -> Scalar<T> {
+        let elements: Vec<Scalar<T>> = self.data.iter().flatten().cloned().collect();
+        Scalar::sum(&elements)
+    }
+
+    // Mean of all the elements
+    pub fn mean(&self) -> Scalar<T> {
+        let elements: Vec<Scalar<T>> = self.data.iter().flatten().cloned().collect();
+        Scalar::mean(&elements)
+    }
+
+    pub fn transpose(&self) -> Tensor2D<T> {
         let mut ans = Self::zeros(self.cols, self.rows, false);
 
         for row in 0..self.rows {
@@ -120,64 +134,241 @@ impl Tensor2D {
         ans
     }
 
-    pub fn backward(&self) -> Vec<Rc<RefCell<Data>>> {
-        // Accumulate the nodes that need to be backpropagated
-        let mut nodes: Vec<Rc<RefCell<Data>>> = Vec::new();
+    // Registers the same hook on every element, it runs on each element's incoming gradient
+    pub fn register_hook(&self, hook: impl Fn(T) -> T + MaybeSync + 'static) {
+        let hook: Shared<HookFn<T>> = Shared::new(hook);
 
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                // Extend the nodes with the backward of the current node
-                nodes.extend(self.data[row][col].backward());
+        for row in self.data.iter() {
+            for scalar in row.iter() {
+                scalar.data.borrow_mut().hooks.push(Hook(Shared::clone(&hook)));
+            }
+        }
+    }
+
+    pub fn backward(&self) -> Vec<Shared<Lock<Data<T>>>> {
+        // Backpropagate from all the elements at once, each seeded with 1.0.
+        // The merged topological order visits the shared subgraph only once.
+        let roots: Vec<Scalar<T>> = self.data.iter().flatten().cloned().collect();
+        let seeds: Vec<T> = vec![T::one(); roots.len()];
+
+        Scalar::backward_many(&roots, &seeds, false)
+    }
+
+    // Like Tensor2D::backward, but keeps the graph, see Scalar::backward_retain_graph
+    pub fn backward_retain_graph(&self) -> Vec<Shared<Lock<Data<T>>>> {
+        let roots: Vec<Scalar<T>> = self.data.iter().flatten().cloned().collect();
+        let seeds: Vec<T> = vec![T::one(); roots.len()];
+
+        Scalar::backward_many(&roots, &seeds, true)
+    }
+
+    // Backpropagates from every element in a single pass, seeding each one with the matching element of `seed`.
+    // This is the vector-Jacobian product of `seed` with the tensor.
+    pub fn backward_with(&self, seed: &Tensor2D<T>) -> Vec<Shared<Lock<Data<T>>>> {
+        assert_eq!(self.rows, seed.rows);
+        assert_eq!(self.cols, seed.cols);
+
+        let roots: Vec<Scalar<T>> = self.data.iter().flatten().cloned().collect();
+        let seeds: Vec<T> = seed.data
+            .iter()
+            .flatten()
+            .map(|scalar| scalar.val())
+            .collect();
+
+        Scalar::backward_many(&roots, &seeds, false)
+    }
+
+    // Functional counterpart of Tensor2D::backward, see grad::grad.
+    // Returns one constant tensor per input, shaped like it, holding d(outputs)/d(input).
+    pub fn grad(outputs: &[Tensor2D<T>], seeds: Option<&[Tensor2D<T>]>, inputs: &[Tensor2D<T>]) -> Vec<Tensor2D<T>> {
+        let flatten = |tensors: &[Tensor2D<T>]| -> Vec<Scalar<T>> {
+            tensors
+                .iter()
+                .flat_map(|tensor| tensor.data.iter().flatten().cloned())
+                .collect()
+        };
+
+        let seeds: Option<Vec<T>> = seeds.map(|seeds| {
+            assert_eq!(outputs.len(), seeds.len(), "One seed per output is required");
+
+            let mut flat = Vec::new();
+            for (output, seed) in outputs.iter().zip(seeds.iter()) {
+                assert_eq!(output.rows, seed.rows);
+                assert_eq!(output.cols, seed.cols);
+                flat.extend(seed.data.iter().flatten().map(|scalar| scalar.val()));
             }
+            flat
+        });
+
+        let mut grads = grad::grad(&flatten(outputs), seeds.as_deref(), &flatten(inputs)).into_iter();
+
+        inputs
+            .iter()
+            .map(|input| {
+                let mut ans = Self::zeros(input.rows, input.cols, false);
+                for row in 0..input.rows {
+                    for col in 0..input.cols {
+                        ans.data[row][col] = grads.next().unwrap();
+                    }
+                }
+                ans
+            })
+            .collect()
+    }
+
+    // Forward mode counterpart of Tensor2D::grad, see grad::jvp.
+    // Each input is paired with a tangent of the same shape, f gets tensors shaped like the inputs
+    // and the products come back shaped like the outputs of f.
+    pub fn jvp(
+        f: impl FnOnce(&[Tensor2D<T>]) -> Vec<Tensor2D<T>>,
+        inputs: &[Tensor2D<T>],
+        tangents: &[Tensor2D<T>]
+    ) -> (Vec<Tensor2D<T>>, Vec<Tensor2D<T>>) {
+        assert_eq!(inputs.len(), tangents.len(), "One tangent per input is required");
+
+        let flatten = |tensors: &[Tensor2D<T>]| -> Vec<Scalar<T>> {
+            tensors
+                .iter()
+                .flat_map(|tensor| tensor.data.iter().flatten().cloned())
+                .collect()
+        };
+
+        // Rebuilds tensors of the given shapes out of a flat list of scalars
+        let unflatten = |shapes: &[(usize, usize)], scalars: Vec<Scalar<T>>| -> Vec<Tensor2D<T>> {
+            let mut scalars = scalars.into_iter();
+            shapes
+                .iter()
+                .map(|&(rows, cols)| {
+                    let mut ans = Self::zeros(rows, cols, false);
+                    for row in 0..rows {
+                        for col in 0..cols {
+                            ans.data[row][col] = scalars.next().unwrap();
+                        }
+                    }
+                    ans
+                })
+                .collect()
+        };
+
+        let mut flat_tangents = Vec::new();
+        for (input, tangent) in inputs.iter().zip(tangents.iter()) {
+            assert_eq!(input.rows, tangent.rows);
+            assert_eq!(input.cols, tangent.cols);
+            flat_tangents.extend(tangent.data.iter().flatten().map(|scalar| scalar.val()));
         }
 
-        nodes
+        let input_shapes: Vec<(usize, usize)> = inputs.iter().map(|input| (input.rows, input.cols)).collect();
+        let mut output_shapes: Vec<(usize, usize)> = Vec::new();
+
+        let (outputs, products) = grad::jvp(
+            |leaves| {
+                let outputs = f(&unflatten(&input_shapes, leaves.to_vec()));
+                output_shapes = outputs.iter().map(|output| (output.rows, output.cols)).collect();
+                flatten(&outputs)
+            },
+            &flatten(inputs),
+            &flat_tangents
+        );
+
+        (unflatten(&output_shapes, outputs), unflatten(&output_shapes, products))
     }
 
-    pub fn nonlinear(tensor: &Self, activation: Activation) -> Tensor2D {
+    pub fn nonlinear(tensor: &Self, activation: Activation<T>) -> Tensor2D<T> {
         let mut ans = Self::zeros(tensor.rows, tensor.cols, false);
 
         for row in 0..tensor.rows {
             for col in 0..tensor.cols {
-                match activation {
-                    Activation::Tanh => {
-                        ans.data[row][col] = tensor.data[row][col].tanh();
-                    }
-                    Activation::Sigmoid => {
-                        ans.data[row][col] = tensor.data[row][col].sigmoid();
-                    }
-                    Activation::ReLU => {
-                        ans.data[row][col] = tensor.data[row][col].relu();
-                    }
-                    Activation::Exp => {
-                        ans.data[row][col] = tensor.data[row][col].exp();
-                    }
-                }
+                ans.data[row][col] = tensor.data[row][col].unary(activation.clone());
             }
         }
 
         ans
     }
 
-    pub fn tanh(&self) -> Tensor2D {
+    pub fn tanh(&self) -> Tensor2D<T> {
         Self::nonlinear(self, Activation::Tanh)
     }
 
-    pub fn sigmoid(&self) -> Tensor2D {
+    pub fn sigmoid(&self) -> Tensor2D<T> {
         Self::nonlinear(self, Activation::Sigmoid)
     }
 
-    pub fn relu(&self) -> Tensor2D {
+    pub fn relu(&self) -> Tensor2D<T> {
         Self::nonlinear(self, Activation::ReLU)
     }
 
-    pub fn exp(&self) -> Tensor2D {
+    pub fn exp(&self) -> Tensor2D<T> {
         Self::nonlinear(self, Activation::Exp)
     }
 
+    pub fn log(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Log)
+    }
+
+    pub fn sqrt(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Sqrt)
+    }
+
+    pub fn abs(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Abs)
+    }
+
+    pub fn sin(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Sin)
+    }
+
+    pub fn cos(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Cos)
+    }
+
+    pub fn softplus(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Softplus)
+    }
+
+    pub fn leaky_relu(&self, slope: T) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::LeakyReLU(slope))
+    }
+
+    pub fn elu(&self, alpha: T) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Elu(alpha))
+    }
+
+    pub fn gelu(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Gelu)
+    }
+
+    pub fn silu(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::SiLU)
+    }
+
+    pub fn hard_tanh(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::HardTanh)
+    }
+
+    pub fn clamp(&self, lo: T, hi: T) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::Clamp(lo, hi))
+    }
+
+    pub fn stop_gradient(&self) -> Tensor2D<T> {
+        Self::nonlinear(self, Activation::StopGradient)
+    }
+
+    // A tensor of leaves with the same values, cut from the graph
+    pub fn detach(&self) -> Tensor2D<T> {
+        let mut ans = Self::zeros(self.rows, self.cols, false);
+
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                ans.data[row][col] = self.data[row][col].detach();
+            }
+        }
+
+        ans
+    }
+
     // The pow of a tensor is a tensor
     // To compute it we have to call pow on each element of the tensor
-    pub fn pow(self, power: usize) -> Tensor2D {
+    pub fn pow(&self, power: T) -> Tensor2D<T> {
         let mut ans = Self::zeros(self.rows, self.cols, false);
 
         for row in 0..self.rows {
@@ -189,3 +380,38 @@ impl Tensor2D {
         ans
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backward_with_seeds_each_element() {
+        let (a, b) = (Scalar::new(2.0, true), Scalar::new(3.0, true));
+        let out = Tensor2D { rows: 1, cols: 2, data: vec![vec![&a * &b, &a + &b]] };
+
+        // One pass over the shared graph gives the vector-Jacobian product of the seeds
+        out.backward_with(&Tensor2D::row(vec![2.0, 3.0]));
+        assert_eq!(a.grad(), 2.0 * 3.0 + 3.0);
+        assert_eq!(b.grad(), 2.0 * 2.0 + 3.0);
+    }
+
+    #[test]
+    fn jvp_matches_reverse_mode() {
+        let x = Tensor2D::row(vec![0.3, -0.8]);
+        let w = Tensor2D::from(vec![vec![0.5, -1.0, 2.0], vec![1.5, 0.25, -0.7]]);
+        let v = Tensor2D::row(vec![0.7, -1.3]);
+
+        let (y, dy) = Tensor2D::jvp(|x| vec![(&x[0] * &w).gelu()], &[x], &[v]);
+        assert_eq!((dy[0].rows, dy[0].cols), (1, 3));
+
+        // Each element is the row of the Jacobian given by reverse mode, dotted with v
+        let leaves = Tensor2D { rows: 1, cols: 2, data: vec![vec![Scalar::new(0.3, true), Scalar::new(-0.8, true)]] };
+        let expected = (&leaves * &w).gelu();
+        for col in 0..3 {
+            let g = grad::grad(&[expected.data[0][col].clone()], None, &leaves.data[0]);
+            assert_eq!(y[0].data[0][col].val(), expected.data[0][col].val());
+            assert!((dy[0].data[0][col].val() - (g[0].val() * 0.7 - g[1].val() * 1.3)).abs() < 1e-5);
+        }
+    }
+}
@@ -1,21 +1,40 @@
+use crate::lib::dense::Tensor;
+use crate::lib::float::Float;
 use crate::lib::grad::Scalar;
 use crate::lib::grad::Activation;
+use crate::lib::gradcheck::GradCheck;
 use crate::lib::ops::Operation;
+use crate::lib::ops::Reduction;
 use crate::lib::tensor::Tensor2D;
 use std::fmt::Display;
 
-impl Display for Scalar {
+impl<T: Float> Display for Scalar<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:.4} [{:.4}]", self.data.borrow().val, self.data.borrow().grad)
+        let data = self.data.borrow();
+        write!(f, "{:.4} [{:.4}]", data.val, data.grad)
     }
 }
-impl Display for Activation {
+impl<T: Float> Display for Activation<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Activation::Exp => write!(f, "exp"),
             Activation::Tanh => write!(f, "tanh"),
             Activation::Sigmoid => write!(f, "sigmoid"),
             Activation::ReLU => write!(f, "relu"),
+            Activation::StopGradient => write!(f, "stop_gradient"),
+            Activation::Log => write!(f, "log"),
+            Activation::Sqrt => write!(f, "sqrt"),
+            Activation::Abs => write!(f, "abs"),
+            Activation::Sin => write!(f, "sin"),
+            Activation::Cos => write!(f, "cos"),
+            Activation::Softplus => write!(f, "softplus"),
+            Activation::LeakyReLU(slope) => write!(f, "leaky_relu({})", slope),
+            Activation::Elu(alpha) => write!(f, "elu({})", alpha),
+            Activation::Gelu => write!(f, "gelu"),
+            Activation::SiLU => write!(f, "silu"),
+            Activation::HardTanh => write!(f, "hard_tanh"),
+            Activation::Pow(power) => write!(f, "pow({})", power),
+            Activation::Clamp(lo, hi) => write!(f, "clamp({}, {})", lo, hi),
         }
     }
 }
@@ -27,11 +46,30 @@ impl Display for Operation {
             Operation::Sub => write!(f, "-"),
             Operation::Mul => write!(f, "*"),
             Operation::Div => write!(f, "/"),
+            Operation::Pow => write!(f, "^"),
+            Operation::Max => write!(f, "max"),
+            Operation::Min => write!(f, "min"),
+            Operation::Gt => write!(f, ">"),
+            Operation::Ge => write!(f, ">="),
+            Operation::Lt => write!(f, "<"),
+            Operation::Le => write!(f, "<="),
+            Operation::Eq => write!(f, "=="),
+            Operation::Ne => write!(f, "!="),
         }
     }
 }
 
-impl Display for Tensor2D {
+impl Display for Reduction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reduction::Sum => write!(f, "sum"),
+            Reduction::Mean => write!(f, "mean"),
+            Reduction::Dot => write!(f, "dot"),
+        }
+    }
+}
+
+impl<T: Float> Display for Tensor2D<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut ans = String::new();
 
@@ -46,3 +84,43 @@ impl Display for Tensor2D {
         write!(f, "{}", ans)
     }
 }
+
+impl<T: Float> Display for Tensor<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let data = self.data.borrow();
+        // One line per row, like Tensor2D, each element followed by its gradient
+        let cols = data.shape.last().copied().unwrap_or(1).max(1);
+        let mut ans = String::new();
+
+        for (i, (val, grad)) in data.vals.iter().zip(data.grads.iter()).enumerate() {
+            if i % cols == 0 {
+                ans.push_str("| ");
+            }
+            ans.push_str(&format!("{:.4} [{:.4}] | ", val, grad));
+            if i % cols == cols - 1 {
+                ans.push('\n');
+            }
+        }
+
+        write!(f, "{}", ans)
+    }
+}
+
+impl<T: Float> Display for GradCheck<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (input, (error, row, col)) in self.worst.iter().enumerate() {
+            writeln!(
+                f,
+                "input {}: worst error {:.2e} at ({}, {}), analytic {:.6} vs numeric {:.6}",
+                input,
+                error,
+                row,
+                col,
+                self.analytic[input][*row][*col],
+                self.numeric[input][*row][*col]
+            )?;
+        }
+
+        Ok(())
+    }
+}
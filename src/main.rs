@@ -1,15 +1,24 @@
 mod lib {
+    pub mod anomaly;
+    pub mod compiled;
+    pub mod custom;
+    pub mod dense;
+    pub mod dot;
+    pub mod float;
     pub mod grad;
+    pub mod gradcheck;
     pub mod loss;
     pub mod macros;
     pub mod nn;
     pub mod ops;
+    pub mod shared;
     pub mod tensor;
     pub mod traits;
 }
 
 use std::vec::Vec;
 
+use lib::grad::no_grad;
 use lib::grad::Activation;
 use lib::grad::Scalar;
 use lib::loss;
@@ -60,10 +69,7 @@ fn main() {
         }
 
         // Zero the gradients
-        for param in nn.params() {
-            let mut data = param.borrow_mut();
-            data.grad = 0.0;
-        }
+        nn.zero_grad();
 
         // Backpropagate gradients
         loss.backward();
@@ -75,12 +81,14 @@ fn main() {
         }
     }
 
-    // Print preds
-    print!("Preds: ");
-    for input in x_train.iter() {
-        for pred in nn.forward(input).data[0].iter() {
-            print!("| {}", pred.val());
+    // Print preds, no need to build the graph here
+    no_grad(|| {
+        print!("Preds: ");
+        for input in x_train.iter() {
+            for pred in nn.forward(input).data[0].iter() {
+                print!("| {}", pred.val());
+            }
         }
-    }
-    println!(" |");
+        println!(" |");
+    });
 }